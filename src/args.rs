@@ -1,9 +1,18 @@
-use crate::{AppError, Command};
+use crate::{
+    AppError, Command, LayerPolicy, Target,
+    backend::Backend,
+    ime_alias::ImeAliases,
+    ime_map::{ImeMap, ImeMapValue},
+    kanata_tcp_types::FakeKeyAction,
+    script::{ScriptImeReceiverConfig, ScriptSource},
+    settings::{self, Settings, TargetSettings},
+    transport::KanataAddress,
+};
 
-#[cfg(all(feature = "fcitx", target_os = "linux"))]
+#[cfg(target_os = "linux")]
 use crate::fcitx::FcitxImeReceiverConfig;
 
-#[cfg(all(not(feature = "fcitx"), target_os = "linux"))]
+#[cfg(target_os = "linux")]
 use crate::ibus::IbusImeReceiverConfig;
 
 #[cfg(all(feature = "winonoff", target_os = "windows"))]
@@ -15,11 +24,12 @@ use crate::win::WindowsImeReceiverConfig;
 #[cfg(target_os = "macos")]
 use crate::mac::MacImeReceiverConfig;
 
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use lexopt::{
     Parser, ValueExt,
-    prelude::{Long, Short},
+    prelude::{Long, Short, Value},
 };
 use log::Level;
 
@@ -27,25 +37,71 @@ fn help_str() -> String {
     "kanata_ime_observer: monitor the IME status and request kanata to change the config file / layer. 
 
 Commands:
-    kanata_ime_observer config <PORT> [-i|--ime] <IME-NAME> [OPTIONS]                                   
+    kanata_ime_observer config <ADDRESS> [-i|--ime] <IME-NAME> [-f|--file] <CONFIG-FILE> [OPTIONS]                                   
         Request kanata to change the config file.
 
-    kanata_ime_observer layer <PORT> [-i|--ime] <IME-NAME> [-l|--layer] <LAYER-NAME> [OPTIONS]          
+    kanata_ime_observer layer <ADDRESS> [-i|--ime] <IME-NAME> [-l|--layer] <LAYER-NAME> [--strict] [--policy <POLICY>] [OPTIONS]          
         Request kanata to change the layer.
 
-    kanata_ime_observer log <PORT> [OPTIONS]
+    kanata_ime_observer fakekey <ADDRESS> [-i|--ime] <IME-NAME> [-k|--key] <KEY-NAME> [--action <ACTION>] [OPTIONS]
+        Request kanata to act on the virtual key.
+
+    kanata_ime_observer log <ADDRESS> [OPTIONS]
         Does not any request to kanata.
+
+    kanata_ime_observer [--config-file <PATH>] [OPTIONS]
+        Run with the mode and the targets in the config file.
+
+Address:
+    <PORT>, <HOST>:<PORT>, [<IPv6>]:<PORT> or unix:<PATH> (unix only).
 ".to_string()
 }
 
 fn options_str() -> String {
-    "Options:
+    "Address:
+    <PORT>, <HOST>:<PORT>, [<IPv6>]:<PORT> or unix:<PATH> (unix only).
+    <PORT> alone connects to 127.0.0.1.
+
+IME name:
+    An exact name, a glob with '*' and '?' (e.g. 'xkb:*'), or a regex after 're:' (e.g. 're:xkb:(\\w+):.*').
+    Exact names are tried first, then the patterns in the given order.
+    The captures of a regex can be used in the layer or key name as '$1' or '${name}'.
+    'lang:' matches the language of the IME (e.g. 'lang:ja', 'lang:zh_TW') with the ibus and fcitx backends.
+    'field=glob' matches the fields of the IME name (e.g. 'layout=us,variant=', 'mode=Romaji*').
+    The fields are framework, layout, variant, language, region and mode. The log subcommand prints them.
+
+Options:
     -h|--help
         Print help
 
     -d|--debug
         Enable debug logging.
 
+    --config-file <PATH> (default $XDG_CONFIG_HOME/kanata_ime_observer/config.toml)
+        The TOML file of the targets, the mode, the mapping and the backend options.
+        Command line options override it. The default file is used only if it exists.
+        The mapping is reloaded when the file changes or on SIGHUP (unix).
+
+    --backend <ibus|fcitx|script|auto> (ibus, fcitx are linux only) (default auto)
+        The IME framework to observe. 'auto' selects the one running on the session bus on linux.
+        'script' reads IME names line by line from '--script'.
+
+    --script <PATH|-> (script only) (default -)
+        The file or FIFO to read IME names from. '-' means stdin.
+
+    --exit-at-eof (script only)
+        Exit at the end of stdin or a regular file. Otherwise keep running and keep the last IME.
+
+    --alias <ALIAS>=<IME-NAME>
+        Use <ALIAS> in the rules for the IME. Exact names are tried before patterns, the alias before the IME name.
+        <IME-NAME> can be a pattern. Replaces the aliases in the config file. Can be repeated.
+
+    --builtin-aliases
+        Use the builtin aliases (japanese, korean, pinyin, us).
+
+    --no-builtin-aliases
+        Don't use the builtin aliases even if the config file enables them.
+
     --polling <MILLISECOND> (win, win_onoff only) (win default 500) (win_onoff default 1000)
         Polling span [ms] of GetKeyboardLayout(win), SendMessageTimeout(win_onoff).
     
@@ -71,7 +127,28 @@ fn config_help_str() -> String {
         "kanata_ime_observer config: monitor the IME status and request kanata to change the config file.
 
 Usage:
-    kanata_ime_observer config <PORT> [-i|--ime] <IME-NAME> [OPTIONS]
+    kanata_ime_observer config <ADDRESS> [-i|--ime] <IME-NAME> [OPTIONS]
+    kanata_ime_observer config <ADDRESS> [-i|--ime] <IME-NAME> [-f|--file] <CONFIG-FILE> [OPTIONS]
+
+    Without '--file', the order of '--ime' is the index of '--cfg' of kanata.
+    With '--file', kanata reloads the given config file.
+
+Config options:
+    --default-config <INDEX>
+        The index of '--cfg' for the IMEs which match no '--ime'.
+
+    --default-file <CONFIG-FILE>
+        The config file for the IMEs which match no '--ime'. Used with '--file'.
+
+    --ignore <IME-NAME>
+        Keep the current config file when switching to the IME, even with '--default-config'.
+
+    --app <APP-NAME> (linux only)
+        The following '--ime' (with '--file') and '--ignore' are only for the focused application, and are tried before the others.
+        <APP-NAME> is the app id or the class on X11, sway and Hyprland. It can be a pattern like <IME-NAME>.
+
+    --target <ADDRESS>
+        Send requests to one more kanata. The following options such as '--ime' are for the new kanata.
 
 {}",
         options_str(),
@@ -83,7 +160,67 @@ fn layer_help_str() -> String {
         "kanata_ime_observer layer: monitor the IME status and request kanata to change the layer.
 
 Usage:
-    kanata_ime_observer layer <PORT> [-i|--ime] <IME-NAME> [-l|--layer] <LAYER-NAME> [OPTIONS]
+    kanata_ime_observer layer <ADDRESS> [-i|--ime] <IME-NAME> [-l|--layer] <LAYER-NAME> [--strict] [--policy <POLICY>] [OPTIONS]
+
+Layer options:
+    --default-layer <LAYER-NAME>
+        The layer for the IMEs which match no '--ime'. Without it, kanata keeps the current layer.
+
+    --ignore <IME-NAME>
+        Keep the current layer when switching to the IME, even with '--default-layer'.
+
+    --app <APP-NAME> (linux only)
+        The following '--ime' and '--ignore' are only for the focused application, and are tried before the others.
+        <APP-NAME> is the app id or the class on X11, sway and Hyprland. It can be a pattern like <IME-NAME>.
+
+    --strict
+        Refuse to start when any kanata does not have a layer given by '--layer'.
+        A kanata which is not running at the start is checked when it connects, and stops the observer.
+
+    --policy <follow|enforce|remember> (default follow)
+        'follow' follows the layer changes made in kanata.
+        'enforce' changes the layer back to the layer of the current IME when kanata changes the layer.
+        'remember' is 'follow', and restores the last layer of the IME when switching back to the IME.
+
+    --grace <MILLISECOND> (enforce only) (default 0)
+        The grace period [ms] before 'enforce' changes the layer back.
+
+    --switch-ime (follow, remember only) (ibus, fcitx only)
+        Switch the IME to the one paired with the layer when kanata changes the layer.
+
+    --target <ADDRESS>
+        Send requests to one more kanata. The following options such as '--ime' are for the new kanata.
+
+{}",
+        options_str()
+    )
+}
+
+fn fakekey_help_str() -> String {
+    format!(
+        "kanata_ime_observer fakekey: monitor the IME status and request kanata to act on the virtual key.
+
+Usage:
+    kanata_ime_observer fakekey <ADDRESS> [-i|--ime] <IME-NAME> [-k|--key] <KEY-NAME> [--action <ACTION>] [OPTIONS]
+
+    The virtual key of the previous IME is released when leaving the IME.
+
+Fakekey options:
+    --default-key <KEY-NAME>
+        The virtual key for the IMEs which match no '--ime'.
+
+    --ignore <IME-NAME>
+        Keep the current virtual key when switching to the IME, even with '--default-key'.
+
+    --app <APP-NAME> (linux only)
+        The following '--ime' and '--ignore' are only for the focused application, and are tried before the others.
+        <APP-NAME> is the app id or the class on X11, sway and Hyprland. It can be a pattern like <IME-NAME>.
+
+    --action <press|tap|toggle> (default press)
+        The action on the virtual key when entering the IME.
+
+    --target <ADDRESS>
+        Send requests to one more kanata. The following options such as '--ime' are for the new kanata.
 
 {}",
         options_str()
@@ -95,7 +232,7 @@ fn log_help_str() -> String {
         "kanata_ime_observer log: does not send any request to kanata.
 
 Usage:
-    kanata_ime_observer log <PORT> [OPTIONS]
+    kanata_ime_observer log <ADDRESS> [OPTIONS]
 
 {}",
         options_str()
@@ -104,14 +241,23 @@ Usage:
 
 #[derive(Debug)]
 pub struct Args {
-    pub port: u16,
-    pub command: Command,
+    pub targets: Vec<Target>,
+    /// 読み込んだ設定ファイル。変更を監視して再読み込みする。
+    pub settings_path: Option<PathBuf>,
     pub log_level: Level,
-    #[cfg(all(feature = "fcitx", target_os = "linux"))]
-    pub app_config: FcitxImeReceiverConfig,
+    pub strict: bool,
+    pub policy: LayerPolicy,
+    /// kanataのレイヤーの変化に合わせてIMEを切り替えるかどうか(ibus, fcitx)。
+    pub switch_ime: bool,
+    pub backend: Backend,
+    pub script_config: ScriptImeReceiverConfig,
+    pub aliases: ImeAliases,
 
-    #[cfg(all(not(feature = "fcitx"), target_os = "linux"))]
-    pub app_config: IbusImeReceiverConfig,
+    #[cfg(target_os = "linux")]
+    pub ibus_config: IbusImeReceiverConfig,
+
+    #[cfg(target_os = "linux")]
+    pub fcitx_config: FcitxImeReceiverConfig,
 
     #[cfg(all(feature = "winonoff", target_os = "windows"))]
     pub app_config: WindowsImeOnOffReceiverConfig,
@@ -125,80 +271,105 @@ pub struct Args {
 
 #[allow(unused_mut)]
 pub fn parse_args() -> Result<Args, AppError> {
+    // 設定ファイルの値をデフォルト値とする。
+    let (settings, settings_path) = load_settings()?;
+
     let mut parser = Parser::from_env();
 
-    #[cfg(all(feature = "fcitx", target_os = "linux"))]
-    let mut app_config = FcitxImeReceiverConfig::default();
+    #[cfg(target_os = "linux")]
+    let ibus_config = settings.ibus.unwrap_or_default();
 
-    #[cfg(all(not(feature = "fcitx"), target_os = "linux"))]
-    let mut app_config = IbusImeReceiverConfig::default();
+    #[cfg(target_os = "linux")]
+    let fcitx_config = settings.fcitx.unwrap_or_default();
 
     #[cfg(all(feature = "winonoff", target_os = "windows"))]
-    let mut app_config = WindowsImeOnOffReceiverConfig::default();
+    let mut app_config = settings.win_onoff.unwrap_or_default();
 
     #[cfg(all(not(feature = "winonoff"), target_os = "windows"))]
-    let mut app_config = WindowsImeReceiverConfig::default();
+    let mut app_config = settings.windows.unwrap_or_default();
 
     #[cfg(target_os = "macos")]
-    let mut app_config = MacImeReceiverConfig::default();
+    let mut app_config = settings.mac.unwrap_or_default();
 
-    let subcommand = parser.value().map_err(|_|{AppError::ArgError("kanata_ime_observer has three subcommand 'kanata_ime_observer config', 'kanata_ime_observer layer' and 'kanata_ime_observer log'.".to_owned())})?;
-
-    let subcommand_name = match subcommand.to_str() {
-        Some("config") => "config",
-        Some("layer") => "layer",
-        Some("log") => "log",
-        Some("-h") | Some("--help") => {
-            println!("{}", help_str());
-            std::process::exit(0);
-        }
-        _ => {
-            return Err(AppError::ArgError("kanata_ime_observer has three subcommand 'kanata_ime_observer config', 'kanata_ime_observer layer' and 'kanata_ime_observer log'.".to_owned()));
-        }
-    };
+    // サブコマンドは設定ファイルのmodeで省略できる。
+    let mut raw_args = parser.raw_args()?;
+    let first_arg = raw_args.peek().and_then(|arg| arg.to_str());
+    if let Some("-h") | Some("--help") = first_arg {
+        println!("{}", help_str());
+        std::process::exit(0);
+    }
 
-    // 第一引数
-    let initial_pos_arg = parser.value().map_err(|_| {
-        AppError::ArgError(format!(
-            "'kanata_ime_observer {subcommand_name}' needs one positional argument 'PORT'."
-        ))
-    })?;
+    let cli_subcommand_name = first_arg.and_then(subcommand_name);
+    if cli_subcommand_name.is_some() {
+        raw_args.next();
+    }
 
-    if let Some(initial_pos_arg_str) = initial_pos_arg.to_str()
-        && let "-h" | "--help" = initial_pos_arg_str
-    {
-        match subcommand_name {
-            "config" => {
-                println!("{}", config_help_str());
-            }
-            "layer" => {
-                println!("{}", layer_help_str());
-            }
-            "log" => {
-                println!("{}", log_help_str());
-            }
-            _ => {}
+    let subcommand_name = match (cli_subcommand_name, settings.mode.as_deref()) {
+        (Some(subcommand_name), _) => subcommand_name,
+        (None, Some(mode)) => subcommand_name(mode).ok_or(AppError::SettingsError(format!(
+            "'mode' must be 'config', 'layer', 'fakekey' or 'log', but '{mode}' was given."
+        )))?,
+        (None, None) => {
+            return Err(AppError::ArgError("kanata_ime_observer has four subcommand 'kanata_ime_observer config', 'kanata_ime_observer layer', 'kanata_ime_observer fakekey' and 'kanata_ime_observer log'.".to_owned()));
         }
-        std::process::exit(0);
-    }
+    };
 
-    // ポート番号の取得
-    let port: u16 = initial_pos_arg
-        .parse()
-        .map_err(|_| AppError::ArgError("Invalid port number.".to_owned()))?;
+    // 接続先。省略した場合は設定ファイルの接続先を利用する。
+    let mut address: Option<KanataAddress> = None;
 
     // その他のデフォルト値など
-    let mut log_level = Level::Info;
+    let mut log_level = if settings.debug == Some(true) {
+        Level::Debug
+    } else {
+        Level::Info
+    };
+    let mut strict = settings.strict.unwrap_or(false);
+    let mut policy_name = match settings.policy.as_deref() {
+        Some(policy_name) => parse_policy_name(Some(policy_name))?,
+        None => "follow",
+    };
+    let mut grace: Option<u64> = None;
+    let mut switch_ime = settings.switch_ime.unwrap_or(false);
+    let mut backend = settings.backend.unwrap_or_default();
+    let mut script_config = settings.script.unwrap_or_default();
+    // コマンドラインの別名は設定ファイルの別名を置き換える。
+    let mut aliases: Vec<(String, Vec<String>)> = Vec::new();
+    let mut builtin_aliases = settings.builtin_aliases.unwrap_or(false);
 
     // for config
-    let mut config_map: HashMap<String, usize> = HashMap::new();
+    let mut default_config: Option<usize> = None;
+
+    // for config --file
+    let mut config_files: Vec<String> = Vec::new();
+    let mut default_file: Option<String> = None;
 
     // for layer
     let mut ime_names: Vec<String> = Vec::new();
+    let mut ignored_ime_names: Vec<String> = Vec::new();
+
+    // --appで指定した、以降の--ime・--ignoreの条件となるアプリケーション
+    let mut app_name: Option<String> = None;
+    let mut ime_apps: Vec<Option<String>> = Vec::new();
+    let mut ignored_apps: Vec<Option<String>> = Vec::new();
     let mut layer_names: Vec<String> = Vec::new();
+    let mut default_layer: Option<String> = None;
+
+    // for fakekey
+    let mut key_names: Vec<String> = Vec::new();
+    let mut default_key: Option<String> = None;
+    let mut action = match settings.action.as_deref() {
+        Some(action) => parse_action(Some(action))?,
+        None => FakeKeyAction::Press,
+    };
+
+    // 完了した接続先のオプション
+    let mut target_args: Vec<TargetArgs> = Vec::new();
 
     while let Some(arg) = parser.next()? {
         match arg {
+            Value(value) if address.is_none() && target_args.is_empty() => {
+                address = Some(value.to_string_lossy().parse()?);
+            }
             Short('i') | Long("ime") => {
                 let ime_name = parser
                     .value()?
@@ -209,18 +380,41 @@ pub fn parse_args() -> Result<Args, AppError> {
                     .to_string();
 
                 match subcommand_name {
-                    "config" => {
-                        let config_number = config_map.len();
-                        if config_map.insert(ime_name, config_number).is_some() {
-                            return Err(AppError::ArgError("Duplicate IME name.".to_string()));
-                        }
-                    }
-                    "layer" => {
+                    "config" | "layer" | "fakekey" => {
                         ime_names.push(ime_name);
+                        ime_apps.push(app_name.clone());
                     }
                     _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
                 }
             }
+            Long("ignore") => match subcommand_name {
+                "config" | "layer" | "fakekey" => {
+                    let ime_name = parser
+                        .value()?
+                        .to_str()
+                        .ok_or(AppError::ArgError(
+                            "This IME name has invalid unicode string.".to_string(),
+                        ))?
+                        .to_string();
+                    ignored_ime_names.push(ime_name);
+                    ignored_apps.push(app_name.clone());
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("app") => match subcommand_name {
+                "config" | "layer" | "fakekey" => {
+                    // 以降の--ime・--ignoreはアプリケーションがフォーカス中の場合のものとする。
+                    let name = parser
+                        .value()?
+                        .to_str()
+                        .ok_or(AppError::ArgError(
+                            "This application name has invalid unicode string.".to_string(),
+                        ))?
+                        .to_string();
+                    app_name = Some(name);
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
             Short('l') | Long("layer") => match subcommand_name {
                 "layer" => {
                     let layer_name = parser
@@ -234,6 +428,120 @@ pub fn parse_args() -> Result<Args, AppError> {
                 }
                 _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
             },
+            Long("default-layer") => match subcommand_name {
+                "layer" => {
+                    let layer_name = parser
+                        .value()?
+                        .to_str()
+                        .ok_or(AppError::ArgError(
+                            "This layer name has invalid unicode string.".to_owned(),
+                        ))?
+                        .to_string();
+                    default_layer = Some(layer_name);
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Short('k') | Long("key") => match subcommand_name {
+                "fakekey" => {
+                    let key_name = parser
+                        .value()?
+                        .to_str()
+                        .ok_or(AppError::ArgError(
+                            "This key name has invalid unicode string.".to_owned(),
+                        ))?
+                        .to_string();
+                    key_names.push(key_name);
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("default-key") => match subcommand_name {
+                "fakekey" => {
+                    let key_name = parser
+                        .value()?
+                        .to_str()
+                        .ok_or(AppError::ArgError(
+                            "This key name has invalid unicode string.".to_owned(),
+                        ))?
+                        .to_string();
+                    default_key = Some(key_name);
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("action") => match subcommand_name {
+                "fakekey" => {
+                    action = parse_action(parser.value()?.to_str())?;
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("target") => match subcommand_name {
+                "config" | "layer" | "fakekey" => {
+                    // 以降のオプションは新しい接続先のものとする。
+                    let new_address: KanataAddress = parser.value()?.to_string_lossy().parse()?;
+
+                    target_args.push(TargetArgs {
+                        address: address
+                            .replace(new_address)
+                            .ok_or(address_error(subcommand_name))?,
+                        config_files: std::mem::take(&mut config_files),
+                        ime_names: std::mem::take(&mut ime_names),
+                        ignored_ime_names: std::mem::take(&mut ignored_ime_names),
+                        ime_apps: std::mem::take(&mut ime_apps),
+                        ignored_apps: std::mem::take(&mut ignored_apps),
+                        layer_names: std::mem::take(&mut layer_names),
+                        key_names: std::mem::take(&mut key_names),
+                        default_config: default_config.take(),
+                        default_file: default_file.take(),
+                        default_layer: default_layer.take(),
+                        default_key: default_key.take(),
+                    });
+                    app_name = None;
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("policy") => match subcommand_name {
+                "layer" => {
+                    policy_name = parse_policy_name(parser.value()?.to_str())?;
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("grace") => match subcommand_name {
+                "layer" => {
+                    grace = Some(parser.value()?.parse()?);
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("strict") => match subcommand_name {
+                "layer" => {
+                    strict = true;
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("switch-ime") => match subcommand_name {
+                "layer" => {
+                    switch_ime = true;
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Short('f') | Long("file") => match subcommand_name {
+                "config" => {
+                    let config_file = parser.value()?;
+                    config_files.push(absolute_config_file(Path::new(&config_file))?);
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("default-config") => match subcommand_name {
+                "config" => {
+                    default_config = Some(parser.value()?.parse()?);
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
+            Long("default-file") => match subcommand_name {
+                "config" => {
+                    let config_file = parser.value()?;
+                    default_file = Some(absolute_config_file(Path::new(&config_file))?);
+                }
+                _ => return Err(AppError::ArgError("Unexpected option.".to_string())),
+            },
             Short('h') | Long("help") => {
                 match subcommand_name {
                     "config" => {
@@ -242,6 +550,9 @@ pub fn parse_args() -> Result<Args, AppError> {
                     "layer" => {
                         println!("{}", layer_help_str());
                     }
+                    "fakekey" => {
+                        println!("{}", fakekey_help_str());
+                    }
                     "log" => {
                         println!("{}", log_help_str());
                     }
@@ -252,6 +563,38 @@ pub fn parse_args() -> Result<Args, AppError> {
             Short('d') | Long("debug") => {
                 log_level = Level::Debug;
             }
+            Long("config-file") => {
+                // load_settingsで読み込み済み。
+                parser.value()?;
+            }
+            Long("backend") => {
+                backend = parser.value()?.parse()?;
+            }
+            Long("alias") => {
+                let alias = parser.value()?;
+                let (alias, ime_name) = alias
+                    .to_str()
+                    .and_then(|alias| alias.split_once('='))
+                    .ok_or(AppError::ArgError(
+                        "'--alias' needs '<ALIAS>=<IME-NAME>'.".to_string(),
+                    ))?;
+                aliases.push((alias.to_string(), vec![ime_name.to_string()]));
+            }
+            Long("builtin-aliases") => {
+                builtin_aliases = true;
+            }
+            Long("no-builtin-aliases") => {
+                builtin_aliases = false;
+            }
+            Long("script") => {
+                let source = parser.value()?;
+                script_config.source = ScriptSource::from(source.to_str().ok_or(
+                    AppError::ArgError("This script path has invalid unicode string.".to_string()),
+                )?);
+            }
+            Long("exit-at-eof") => {
+                script_config.exit_at_eof = true;
+            }
             #[cfg(target_os = "windows")]
             Long("polling") => {
                 let polling_span: u64 = parser.value()?.parse()?;
@@ -285,47 +628,446 @@ pub fn parse_args() -> Result<Args, AppError> {
         }
     }
 
+    let has_target_options = !ime_names.is_empty()
+        || !ignored_ime_names.is_empty()
+        || !layer_names.is_empty()
+        || !key_names.is_empty()
+        || default_config.is_some()
+        || default_file.is_some()
+        || default_layer.is_some()
+        || default_key.is_some();
+    match address {
+        Some(address) => target_args.push(TargetArgs {
+            address,
+            config_files,
+            ime_names,
+            ignored_ime_names,
+            ime_apps,
+            ignored_apps,
+            layer_names,
+            key_names,
+            default_config,
+            default_file,
+            default_layer,
+            default_key,
+        }),
+        None if !has_target_options && !settings.targets.is_empty() => {
+            for target in settings.targets {
+                target_args.push(target_args_from_settings(subcommand_name, target)?);
+            }
+        }
+        None => return Err(address_error(subcommand_name)),
+    }
+
+    let policy = match (policy_name, grace) {
+        ("enforce", grace) => LayerPolicy::Enforce {
+            grace: Duration::from_millis(grace.or(settings.grace).unwrap_or(0)),
+        },
+        ("remember", None) => LayerPolicy::Remember,
+        (_, None) => LayerPolicy::Follow,
+        (_, Some(_)) => {
+            return Err(AppError::ArgError(
+                "'--grace' needs '--policy enforce'.".to_string(),
+            ));
+        }
+    };
+
+    if switch_ime && matches!(policy, LayerPolicy::Enforce { .. }) {
+        return Err(AppError::ArgError(
+            "'--switch-ime' needs '--policy follow' or '--policy remember'.".to_string(),
+        ));
+    }
+
+    if aliases.is_empty() {
+        aliases = settings.alias;
+    }
+    let aliases = ImeAliases::new(aliases, builtin_aliases)?;
+
+    let mut targets: Vec<Target> = Vec::new();
+    for target_arg in target_args.into_iter() {
+        if targets
+            .iter()
+            .any(|target| target.address == target_arg.address)
+        {
+            return Err(AppError::ArgError(format!(
+                "Duplicate address '{}'.",
+                target_arg.address
+            )));
+        }
+
+        targets.push(Target {
+            address: target_arg.address.clone(),
+            command: build_command(subcommand_name, target_arg, action)?,
+        });
+    }
+
+    Ok(Args {
+        targets,
+        settings_path,
+        log_level,
+        strict,
+        policy,
+        switch_ime,
+        backend,
+        script_config,
+        aliases,
+        #[cfg(target_os = "linux")]
+        ibus_config,
+        #[cfg(target_os = "linux")]
+        fcitx_config,
+        #[cfg(not(target_os = "linux"))]
+        app_config,
+    })
+}
+
+/// `--config-file`、なければ既定のパスの設定ファイルを読み込む。既定のパスにない場合は空の設定とする。
+fn load_settings() -> Result<(Settings, Option<PathBuf>), AppError> {
+    // 他の引数より先に`--config-file`を探す。
+    let mut parser = Parser::from_env();
+    let mut path: Option<PathBuf> = None;
+    while let Ok(Some(arg)) = parser.next() {
+        match arg {
+            Long("config-file") => path = Some(parser.value()?.into()),
+            Value(_) => {}
+            _ => {
+                // 他のオプションの値は読み飛ばす。
+                let _ = parser.optional_value();
+            }
+        }
+    }
+
+    let path = path.or(settings::default_path().filter(|path| path.exists()));
+    match path {
+        Some(path) => Ok((Settings::load(&path)?, Some(path))),
+        None => Ok((Settings::default(), None)),
+    }
+}
+
+fn subcommand_name(name: &str) -> Option<&'static str> {
+    match name {
+        "config" => Some("config"),
+        "layer" => Some("layer"),
+        "fakekey" => Some("fakekey"),
+        "log" => Some("log"),
+        _ => None,
+    }
+}
+
+fn address_error(subcommand_name: &str) -> AppError {
+    AppError::ArgError(format!(
+        "'kanata_ime_observer {subcommand_name}' needs one positional argument 'ADDRESS'."
+    ))
+}
+
+fn parse_policy_name(policy_name: Option<&str>) -> Result<&'static str, AppError> {
+    match policy_name {
+        Some("follow") => Ok("follow"),
+        Some("enforce") => Ok("enforce"),
+        Some("remember") => Ok("remember"),
+        _ => Err(AppError::ArgError(
+            "'--policy' must be 'follow', 'enforce' or 'remember'.".to_string(),
+        )),
+    }
+}
+
+fn parse_action(action: Option<&str>) -> Result<FakeKeyAction, AppError> {
+    match action {
+        Some("press") => Ok(FakeKeyAction::Press),
+        Some("tap") => Ok(FakeKeyAction::Tap),
+        Some("toggle") => Ok(FakeKeyAction::Toggle),
+        _ => Err(AppError::ArgError(
+            "'--action' must be 'press', 'tap' or 'toggle'.".to_string(),
+        )),
+    }
+}
+
+/// kanataの作業ディレクトリに依存しないように設定ファイルを絶対パスにする。
+fn absolute_config_file(config_file: &Path) -> Result<String, AppError> {
+    let absolute_config_file = std::fs::canonicalize(config_file).map_err(|_| {
+        AppError::ArgError(format!(
+            "The config file '{}' does not exist.",
+            config_file.display()
+        ))
+    })?;
+
+    Ok(absolute_config_file
+        .to_str()
+        .ok_or(AppError::ArgError(
+            "This config file path has invalid unicode string.".to_owned(),
+        ))?
+        .to_string())
+}
+
+/// 設定ファイルの接続先から、モードに応じた接続先ごとのオプションを作成する。
+fn target_args_from_settings(
+    subcommand_name: &str,
+    target: TargetSettings,
+) -> Result<TargetArgs, AppError> {
+    let TargetSettings {
+        address,
+        config,
+        file,
+        layer,
+        key,
+        ignore,
+        default_config,
+        default_file,
+        default_layer,
+        default_key,
+        apps,
+    } = target;
+
+    let mut config_files: Vec<String> = Vec::new();
+    let mut ime_names: Vec<String> = Vec::new();
+    let mut ignored_ime_names: Vec<String> = Vec::new();
+    let mut ime_apps: Vec<Option<String>> = Vec::new();
+    let mut ignored_apps: Vec<Option<String>> = Vec::new();
+    let mut layer_names: Vec<String> = Vec::new();
+    let mut key_names: Vec<String> = Vec::new();
+    let default_file = default_file
+        .as_deref()
+        .map(absolute_config_file)
+        .transpose()?;
+
+    let use_file =
+        !file.is_empty() || default_file.is_some() || apps.iter().any(|app| !app.file.is_empty());
+    if subcommand_name == "config" && !use_file {
+        ime_apps = vec![None; config.len()];
+        ime_names = config;
+    }
+
+    // 全体のマップの後にアプリケーションごとのマップを続ける。
+    let sections = std::iter::once((None, file, layer, key, ignore)).chain(
+        apps.into_iter()
+            .map(|app| (Some(app.name), app.file, app.layer, app.key, app.ignore)),
+    );
+    for (app_name, file, layer, key, ignore) in sections {
+        match subcommand_name {
+            "config" if use_file => {
+                for (ime_name, config_file) in file {
+                    config_files.push(absolute_config_file(&config_file)?);
+                    ime_names.push(ime_name);
+                    ime_apps.push(app_name.clone());
+                }
+            }
+            "layer" => {
+                for (ime_name, layer_name) in layer {
+                    layer_names.push(layer_name);
+                    ime_names.push(ime_name);
+                    ime_apps.push(app_name.clone());
+                }
+            }
+            "fakekey" => {
+                for (ime_name, key_name) in key {
+                    key_names.push(key_name);
+                    ime_names.push(ime_name);
+                    ime_apps.push(app_name.clone());
+                }
+            }
+            _ => {}
+        }
+        for ime_name in ignore {
+            ignored_ime_names.push(ime_name);
+            ignored_apps.push(app_name.clone());
+        }
+    }
+
+    let missing = match subcommand_name {
+        "config" if default_config.is_none() && default_file.is_none() => "'config' or 'file'",
+        "layer" if default_layer.is_none() => "'layer'",
+        "fakekey" if default_key.is_none() => "'key'",
+        _ => "",
+    };
+    if ime_names.is_empty() && !missing.is_empty() {
+        return Err(AppError::SettingsError(format!(
+            "The target '{address}' needs {missing} for the mode '{subcommand_name}'."
+        )));
+    }
+
+    Ok(TargetArgs {
+        address,
+        config_files,
+        ime_names,
+        ignored_ime_names,
+        ime_apps,
+        ignored_apps,
+        layer_names,
+        key_names,
+        default_config,
+        default_file,
+        default_layer,
+        default_key,
+    })
+}
+
+/// 接続先ごとのオプション。
+struct TargetArgs {
+    address: KanataAddress,
+    config_files: Vec<String>,
+    ime_names: Vec<String>,
+    /// 現在のレイヤーのままとするIME名。
+    ignored_ime_names: Vec<String>,
+    /// ime_names・ignored_ime_namesの条件となるアプリケーション。
+    ime_apps: Vec<Option<String>>,
+    ignored_apps: Vec<Option<String>>,
+    layer_names: Vec<String>,
+    key_names: Vec<String>,
+    /// どのIMEにも一致しない場合の値。
+    default_config: Option<usize>,
+    default_file: Option<String>,
+    default_layer: Option<String>,
+    default_key: Option<String>,
+}
+
+/// アプリケーションとIME名と値の組から、アプリケーションごとのマップを含むImeMapを作成する。
+fn build_ime_map<T: ImeMapValue>(
+    entries: Vec<(Option<String>, String, T)>,
+    ignored: Vec<(Option<String>, String)>,
+    default: Option<T>,
+) -> Result<ImeMap<T>, AppError> {
+    let app_map = |app_name: Option<&String>| {
+        ImeMap::new(
+            entries
+                .iter()
+                .filter(|(entry_app, _, _)| entry_app.as_ref() == app_name)
+                .map(|(_, ime_name, value)| (ime_name.clone(), value.clone())),
+        )?
+        .with_ignored(
+            ignored
+                .iter()
+                .filter(|(entry_app, _)| entry_app.as_ref() == app_name)
+                .map(|(_, ime_name)| ime_name.clone()),
+        )
+    };
+
+    // アプリケーションは最初に指定した順に優先する。
+    let mut app_names: Vec<&String> = Vec::new();
+    for app_name in entries
+        .iter()
+        .map(|(app_name, _, _)| app_name)
+        .chain(ignored.iter().map(|(app_name, _)| app_name))
+        .flatten()
+    {
+        if !app_names.contains(&app_name) {
+            app_names.push(app_name);
+        }
+    }
+
+    let mut ime_map = app_map(None)?;
+    for app_name in app_names {
+        ime_map = ime_map.with_app(app_name.clone(), app_map(Some(app_name))?)?;
+    }
+    Ok(ime_map.with_default(default))
+}
+
+/// 接続先ごとのオプションからコマンドを作成する。
+fn build_command(
+    subcommand_name: &str,
+    target_args: TargetArgs,
+    action: FakeKeyAction,
+) -> Result<Command, AppError> {
+    let TargetArgs {
+        address: _,
+        config_files,
+        ime_names,
+        ignored_ime_names,
+        ime_apps,
+        ignored_apps,
+        layer_names,
+        key_names,
+        default_config,
+        default_file,
+        default_layer,
+        default_key,
+    } = target_args;
+
+    let ignored = ignored_apps.into_iter().zip(ignored_ime_names).collect();
+
     match subcommand_name {
         "config" => {
-            if config_map.is_empty() {
+            if ime_names.is_empty() && default_config.is_none() && default_file.is_none() {
                 println!("{}", config_help_str());
                 std::process::exit(0);
             }
 
-            Ok(Args {
-                port,
-                command: Command::Config(config_map),
-                log_level,
-                app_config,
-            })
+            let command = if config_files.is_empty() && default_file.is_none() {
+                if ime_apps.iter().any(Option::is_some) {
+                    return Err(AppError::ArgError(
+                        "'--ime' after '--app' needs '--file' in 'kanata_ime_observer config'."
+                            .to_string(),
+                    ));
+                }
+
+                // --imeの順番がkanataの--cfgの番号となる。
+                Command::Config(build_ime_map(
+                    ime_names
+                        .into_iter()
+                        .enumerate()
+                        .map(|(config_number, ime_name)| (None, ime_name, config_number))
+                        .collect(),
+                    ignored,
+                    default_config,
+                )?)
+            } else {
+                if ime_names.len() != config_files.len() {
+                    return Err(AppError::ArgError("'kanata_ime_observer config --file' needs the same number of IME names and config files.".to_string()));
+                }
+                if default_config.is_some() {
+                    return Err(AppError::ArgError(
+                        "'--default-config' can't be used with '--file'. Use '--default-file'."
+                            .to_string(),
+                    ));
+                }
+
+                Command::ConfigFile(build_ime_map(
+                    zip_entries(ime_apps, ime_names, config_files),
+                    ignored,
+                    default_file,
+                )?)
+            };
+
+            Ok(command)
         }
         "layer" => {
             if ime_names.len() != layer_names.len() {
                 return Err(AppError::ArgError("'kanata_ime_observer layer' needs the same number of IME names and layer names.".to_string()));
             }
 
-            let mut layer_map: HashMap<String, String> = HashMap::new();
-            for (ime_name, layer_name) in ime_names.into_iter().zip(layer_names.into_iter()) {
-                if layer_map.insert(ime_name, layer_name).is_some() {
-                    return Err(AppError::ArgError("Duplicate IME name.".to_string()));
-                }
+            Ok(Command::Layer(build_ime_map(
+                zip_entries(ime_apps, ime_names, layer_names),
+                ignored,
+                default_layer,
+            )?))
+        }
+        "fakekey" => {
+            if ime_names.len() != key_names.len() {
+                return Err(AppError::ArgError("'kanata_ime_observer fakekey' needs the same number of IME names and key names.".to_string()));
             }
 
-            Ok(Args {
-                port,
-                command: Command::Layer(layer_map),
-                log_level,
-                app_config,
-            })
+            let key_map = build_ime_map(
+                zip_entries(ime_apps, ime_names, key_names),
+                ignored,
+                default_key,
+            )?;
+
+            Ok(Command::FakeKey { key_map, action })
         }
-        "log" => Ok(Args {
-            port,
-            command: Command::Log,
-            log_level,
-            app_config,
-        }),
+        "log" => Ok(Command::Log),
         _ => {
             unreachable!();
         }
     }
 }
+
+fn zip_entries<T>(
+    ime_apps: Vec<Option<String>>,
+    ime_names: Vec<String>,
+    values: Vec<T>,
+) -> Vec<(Option<String>, String, T)> {
+    ime_apps
+        .into_iter()
+        .zip(ime_names)
+        .zip(values)
+        .map(|((app_name, ime_name), value)| (app_name, ime_name, value))
+        .collect()
+}
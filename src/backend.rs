@@ -0,0 +1,102 @@
+use crate::AppError;
+
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// IMEを監視するバックエンド。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Backend {
+    /// 実行環境から自動で選択する。
+    #[default]
+    Auto,
+
+    /// ibus(linux)。
+    #[cfg(target_os = "linux")]
+    Ibus,
+
+    /// fcitx5(linux)。
+    #[cfg(target_os = "linux")]
+    Fcitx,
+
+    /// 各OSのIME。
+    #[cfg(not(target_os = "linux"))]
+    Native,
+
+    /// 標準入力・ファイル・FIFOから1行ずつIME名を読み込む。
+    Script,
+}
+
+impl Backend {
+    /// Autoを実際に利用するバックエンドに解決する。返り値はAutoにならない。
+    pub fn resolve(self) -> Result<Backend, AppError> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Backend::Auto => detect_linux_backend(),
+            #[cfg(not(target_os = "linux"))]
+            Backend::Auto => Ok(Backend::Native),
+            backend => Ok(backend),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Backend::Auto),
+            "script" => Ok(Backend::Script),
+            #[cfg(target_os = "linux")]
+            "ibus" => Ok(Backend::Ibus),
+            #[cfg(target_os = "linux")]
+            "fcitx" => Ok(Backend::Fcitx),
+            _ => Err(AppError::ArgError(format!("Unknown backend '{s}'."))),
+        }
+    }
+}
+
+impl TryFrom<String> for Backend {
+    type Error = AppError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// セッションバス上のサービスからibusとfcitx5のどちらが動いているかを調べる。
+/// fcitx5はibusのフロントエンドを持つことがあるため、fcitx5を優先する。
+#[cfg(target_os = "linux")]
+fn detect_linux_backend() -> Result<Backend, AppError> {
+    use dbus::blocking::SyncConnection;
+    use log::info;
+    use std::time::Duration;
+
+    let conn = SyncConnection::new_session()?;
+
+    let proxy = conn.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_millis(500),
+    );
+
+    let has_owner = |name: &str| -> Result<bool, AppError> {
+        let (has_owner,): (bool,) =
+            proxy.method_call("org.freedesktop.DBus", "NameHasOwner", (name,))?;
+        Ok(has_owner)
+    };
+
+    if has_owner("org.fcitx.Fcitx5")? {
+        info!("Detected fcitx5 on the session bus.");
+        Ok(Backend::Fcitx)
+    } else if has_owner("org.freedesktop.IBus")? {
+        info!("Detected ibus on the session bus.");
+        Ok(Backend::Ibus)
+    } else {
+        Err(AppError::DbusError(
+            "Neither 'org.fcitx.Fcitx5' nor 'org.freedesktop.IBus' was found on the session bus."
+                .to_string(),
+        ))
+    }
+}
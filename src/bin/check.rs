@@ -1,33 +1,23 @@
-use kanata_ime_observer::{catch_fatal_error, initialize_app, initialize_fatal_error};
-
-#[cfg(all(feature = "fcitx", target_os = "linux"))]
-use kanata_ime_observer::fcitx::{
-    FcitxImeReceiver as Receiver, FcitxImeReceiverConfig as Config, dbus_main_loop as main_loop,
+use kanata_ime_observer::{
+    ImeMainLoop, ImeReceiver, backend::Backend, catch_fatal_error, initialize_app,
+    initialize_fatal_error, script::ScriptImeReceiver,
 };
 
-#[cfg(all(not(feature = "fcitx"), target_os = "linux"))]
-use kanata_ime_observer::ibus::{
-    IbusImeReceiver as Receiver, IbusImeReceiverConfig as Config, dbus_main_loop as main_loop,
-};
+#[cfg(target_os = "linux")]
+use kanata_ime_observer::{fcitx::FcitxImeReceiver, ibus::IbusImeReceiver};
 
 #[cfg(all(feature = "winonoff", target_os = "windows"))]
-use kanata_ime_observer::win_onoff::{
-    WindowsImeOnOffReceiver as Receiver, WindowsImeOnOffReceiverConfig as Config,
-    win_main_loop as main_loop,
-};
+use kanata_ime_observer::win_onoff::WindowsImeOnOffReceiver as Receiver;
 
 #[cfg(all(not(feature = "winonoff"), target_os = "windows"))]
-use kanata_ime_observer::win::{
-    WindowsImeReceiver as Receiver, WindowsImeReceiverConfig as Config, win_main_loop as main_loop,
-};
+use kanata_ime_observer::win::WindowsImeReceiver as Receiver;
 
 #[cfg(target_os = "macos")]
-use kanata_ime_observer::mac::{
-    MacImeReceiver as Receiver, MacImeReceiverConfig as Config, mac_main_loop as main_loop,
-};
+use kanata_ime_observer::mac::MacImeReceiver as Receiver;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (app_message_receiver, app_fatal_error_receiver) = initialize_app()?;
+fn check<R: ImeReceiver + ImeMainLoop + Send + 'static>() -> Result<(), Box<dyn std::error::Error>>
+{
+    let (context, app_message_receiver, app_fatal_error_receiver) = initialize_app();
 
     let fatal_error = initialize_fatal_error(&app_fatal_error_receiver);
 
@@ -40,9 +30,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
-    let app_config = Config::default();
+    let app_config = R::Config::default();
 
-    let mut ime_receiver = Receiver::new(app_message_receiver, &app_config, &fatal_error)?;
+    let mut ime_receiver = R::new(&context, app_message_receiver, &app_config, &fatal_error)?;
 
     std::thread::spawn({
         let fatal_error = fatal_error.clone();
@@ -56,7 +46,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
-    let _ = main_loop(&fatal_error);
+    let _ = R::main_loop(&context, &app_config, &fatal_error);
 
     Ok(())
 }
+
+/// 第一引数でバックエンドを指定できる(省略時はauto)。
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let backend = match std::env::args().nth(1) {
+        Some(backend) => backend.parse::<Backend>()?,
+        None => Backend::default(),
+    };
+
+    match backend.resolve()? {
+        #[cfg(target_os = "linux")]
+        Backend::Ibus => check::<IbusImeReceiver>(),
+        #[cfg(target_os = "linux")]
+        Backend::Fcitx => check::<FcitxImeReceiver>(),
+        #[cfg(not(target_os = "linux"))]
+        Backend::Native => check::<Receiver>(),
+        Backend::Script => check::<ScriptImeReceiver>(),
+        Backend::Auto => unreachable!("Backend::resolve never returns Backend::Auto."),
+    }
+}
@@ -1,70 +1,950 @@
 use kanata_ime_observer::{
-    AppError, Command, FatalError, Message, catch_fatal_error, initialize_app,
-    initialize_fatal_error,
-    kanata_tcp_types::{KanataClientMessage, KanataServerResponse},
+    AppError, Command, EventReceiver, EventSender, FatalError, ImeMainLoop, ImeReceiver,
+    LayerPolicy, Message, ObserverEvent, Target,
+    backend::Backend,
+    catch_fatal_error, handle_try_send,
+    ime_alias::ImeAliases,
+    ime_id::ImeId,
+    ime_map::{ImeContext, ImeMap},
+    initialize_app, initialize_fatal_error,
+    kanata_tcp_types::{
+        FakeKeyAction, KanataClientMessage, KanataResponseStatus, KanataServerMessage,
+        KanataServerResponse,
+    },
+    script::ScriptImeReceiver,
     send_fatal_error, send_message,
+    transport::{KanataAddress, KanataStream},
+    watch::watch_settings,
 };
 
-#[cfg(all(feature = "fcitx", target_os = "linux"))]
-use kanata_ime_observer::fcitx::{FcitxImeReceiver as Receiver, dbus_main_loop as main_loop};
-
-#[cfg(all(not(feature = "fcitx"), target_os = "linux"))]
-use kanata_ime_observer::ibus::{IbusImeReceiver as Receiver, dbus_main_loop as main_loop};
+#[cfg(target_os = "linux")]
+use kanata_ime_observer::{
+    fcitx::FcitxImeReceiver,
+    focus::{FocusProvider, watch_focus},
+    ibus::IbusImeReceiver,
+};
 
 #[cfg(all(feature = "winonoff", target_os = "windows"))]
-use kanata_ime_observer::win_onoff::{
-    WindowsImeOnOffReceiver as Receiver, win_main_loop as main_loop,
-};
+use kanata_ime_observer::win_onoff::WindowsImeOnOffReceiver as Receiver;
 
 #[cfg(all(not(feature = "winonoff"), target_os = "windows"))]
-use kanata_ime_observer::win::{WindowsImeReceiver as Receiver, win_main_loop as main_loop};
+use kanata_ime_observer::win::WindowsImeReceiver as Receiver;
 
 #[cfg(target_os = "macos")]
-use kanata_ime_observer::mac::{MacImeReceiver as Receiver, mac_main_loop as main_loop};
+use kanata_ime_observer::mac::MacImeReceiver as Receiver;
 
-use log::{debug, error, info};
+use backon::{BackoffBuilder, ExponentialBackoff, ExponentialBuilder};
+use log::{debug, error, info, warn};
 
+use std::collections::{HashMap, VecDeque};
 use std::io::{BufRead, BufReader, Write};
-use std::net::TcpStream;
-use std::sync::Arc;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TrySendError, sync_channel};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-fn write_to_kanata(
-    receiver: &mut Receiver,
-    command: &Command,
-    mut kanata_stream: TcpStream,
+/// IMEの状態の変化をイベントとして全ての接続先へ送る。
+/// 接続先のスレッドが終了していてもIMEの監視は続ける。IME名と別名をログに残す。
+/// logではルールを書くためにIME名のフィールドと言語もログに残す。
+fn forward_ime_status<R: ImeReceiver>(
+    receiver: &mut R,
+    latest_imes: &[Arc<LatestEvent<String>>],
+    aliases: &ImeAliases,
+    is_log: bool,
     fatal_error: &FatalError,
 ) -> Result<(), AppError> {
     while fatal_error.is_none() {
         let ime_status = receiver.receive()?;
-        info!("Change of IME status was detected. ime status: \"{ime_status}\".");
-
-        let msg = match &command {
-            Command::Config(config_map) => config_map
-                .get(&ime_status)
-                .map(|config_num| KanataClientMessage::ReloadNum { index: *config_num }),
-            Command::Layer(layer_map) => {
-                layer_map
-                    .get(&ime_status)
-                    .map(|layer_name| KanataClientMessage::ChangeLayer {
-                        new: layer_name.to_owned(),
-                    })
-            }
-            Command::Log => None,
+        let mut details = format!("ime status: \"{ime_status}\"");
+        if let Some(alias) = aliases.alias(&ime_status) {
+            details.push_str(&format!(", alias: \"{alias}\""));
+        }
+        if is_log {
+            let ime_id = ImeId::parse(&ime_status);
+            if !ime_id.is_empty() {
+                details.push_str(&format!(", fields: \"{ime_id}\""));
+            }
+            if let Some(language) = R::ime_language(&ime_status) {
+                details.push_str(&format!(", language: \"{language}\""));
+            }
+        }
+        info!("Change of IME status was detected. {details}.");
+
+        for latest_ime in latest_imes.iter() {
+            latest_ime.set(ime_status.clone());
+        }
+    }
+    Err(AppError::CaughtFatalError {
+        location: "forward_ime_status".to_string(),
+    })
+}
+
+/// 接続先ごとのまだ送っていない最新の値(IME・フォーカス中のアプリケーション)。
+/// イベントキューが満杯の間の変化は最新の値にまとめ、最新の値は必ず送る。
+struct LatestEvent<T> {
+    value: Mutex<Option<T>>,
+    changed: Condvar,
+}
+
+impl<T: Send + 'static> LatestEvent<T> {
+    fn new() -> Self {
+        Self {
+            value: Mutex::new(None),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// 接続先へ送るスレッドを起動する。
+    fn spawn(event_sender: EventSender, to_event: fn(T) -> ObserverEvent) -> Arc<Self> {
+        let latest = Arc::new(Self::new());
+        std::thread::spawn({
+            let latest = Arc::clone(&latest);
+            move || latest.forward(&event_sender, to_event)
+        });
+        latest
+    }
+
+    fn set(&self, value: T) {
+        *self.value.lock().expect("latest event mutex was poisoned.") = Some(value);
+        self.changed.notify_one();
+    }
+
+    /// 最新の値をブロッキングして送る。接続先のスレッドが終了すると終了する。
+    fn forward(&self, event_sender: &EventSender, to_event: fn(T) -> ObserverEvent) {
+        loop {
+            let value = self
+                .changed
+                .wait_while(
+                    self.value.lock().expect("latest event mutex was poisoned."),
+                    |value| value.is_none(),
+                )
+                .expect("latest event mutex was poisoned.")
+                .take()
+                .expect("the latest event was not set.");
+
+            if event_sender.send(to_event(value)).is_err() {
+                debug!("The connection stopped. Stop sending the events.");
+                return;
+            }
+        }
+    }
+}
+
+/// フォーカス中のアプリケーションの変化をイベントとして全ての接続先へ送る。
+/// logではアプリケーション名を調べるためにログを残す。
+fn forward_focus(event_senders: Vec<EventSender>, has_app_rules: bool) {
+    #[cfg(target_os = "linux")]
+    {
+        let Some(provider) = FocusProvider::detect() else {
+            if has_app_rules {
+                warn!(
+                    "Couldn't find X11, sway or Hyprland. Rules for the focused application never match."
+                );
+            }
+            return;
+        };
+
+        let latest_focuses = event_senders
+            .into_iter()
+            .map(|event_sender| LatestEvent::spawn(event_sender, ObserverEvent::Focus))
+            .collect::<Vec<_>>();
+
+        watch_focus(provider, move |app_name| {
+            info!(
+                "Change of the focused application was detected. application: \"{}\".",
+                app_name.as_deref().unwrap_or_default()
+            );
+            for latest_focus in latest_focuses.iter() {
+                latest_focus.set(app_name.clone());
+            }
+        });
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = event_senders;
+        if has_app_rules {
+            warn!(
+                "The focused application is only supported on linux. Rules for the focused application never match."
+            );
+        }
+    }
+}
+
+/// kanataの応答を待つ時間。応答を返さないkanataのために、これを過ぎたリクエストは破棄する。
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// kanataが設定を再読み込みした直後のレイヤーの変化をrememberで記憶しない時間。
+const RELOAD_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// レイヤーの変化に合わせて切り替えたIMEの変化を待つ時間。これを過ぎたIMEの変化はkanataに送る。
+const SWITCH_ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// kanataへメッセージを送信する。kanataは改行区切りで読み込む。
+fn write_message(
+    kanata_stream: &mut KanataStream,
+    msg: &KanataClientMessage,
+) -> Result<(), AppError> {
+    let mut buf = serde_json::to_string(msg)?;
+    buf.push('\n');
+    kanata_stream.write_all(buf.as_bytes())?;
+    Ok(())
+}
+
+/// ログ用のリクエストの説明。
+fn describe_request(msg: &KanataClientMessage) -> String {
+    match msg {
+        KanataClientMessage::ChangeLayer { new } => format!("the layer \"{new}\""),
+        KanataClientMessage::ReloadNum { index } => format!("the config index {index}"),
+        KanataClientMessage::ReloadFile { path } => format!("the config file \"{path}\""),
+        KanataClientMessage::ActOnFakeKey { name, action } => {
+            format!("{action:?} of the virtual key \"{name}\"")
+        }
+        msg => format!("{msg:?}"),
+    }
+}
+
+/// kanataへ送信したリクエスト。
+struct Request {
+    msg: KanataClientMessage,
+    /// リクエストの原因となったIME。
+    ime_status: String,
+    /// 送信時の世代。新しいリクエストで置き換えられたリクエストは再送しない。
+    generation: u64,
+    sent_at: Instant,
+    backoff: ExponentialBackoff,
+}
+
+/// kanataへのリクエストと応答を対応付け、失敗したリクエストを再送する。kanataは受信した順に応答する。
+struct RequestQueue {
+    pending: VecDeque<Request>,
+    retries: Vec<(Request, Instant)>,
+    generation: u64,
+    /// 送信したレイヤーと時刻。kanataから届く自身のリクエストによるレイヤーの変化を見分ける。
+    echoes: VecDeque<(String, Instant)>,
+}
+
+impl RequestQueue {
+    fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+            retries: Vec::new(),
+            generation: 0,
+            echoes: VecDeque::new(),
+        }
+    }
+
+    /// リクエストを送信し、応答を待つ。
+    fn send(
+        &mut self,
+        kanata_stream: &mut KanataStream,
+        msg: KanataClientMessage,
+        ime_status: &str,
+    ) -> Result<(), AppError> {
+        let request = Request {
+            msg,
+            ime_status: ime_status.to_string(),
+            generation: self.generation,
+            sent_at: Instant::now(),
+            backoff: ExponentialBuilder::default()
+                .with_min_delay(Duration::from_millis(200))
+                .with_max_delay(Duration::from_secs(5))
+                .with_max_times(5)
+                .build(),
+        };
+        info!(
+            "Request {} for the IME \"{}\".",
+            describe_request(&request.msg),
+            request.ime_status
+        );
+        self.write(kanata_stream, request)
+    }
+
+    fn write(
+        &mut self,
+        kanata_stream: &mut KanataStream,
+        mut request: Request,
+    ) -> Result<(), AppError> {
+        write_message(kanata_stream, &request.msg)?;
+        request.sent_at = Instant::now();
+        if let KanataClientMessage::ChangeLayer { new } = &request.msg {
+            self.echoes.push_back((new.to_owned(), request.sent_at));
+        }
+        self.pending.push_back(request);
+        Ok(())
+    }
+
+    /// 古いリクエストの再送を取り消し、IMEに対応するリクエストを送信する。
+    fn send_all(
+        &mut self,
+        kanata_stream: &mut KanataStream,
+        msgs: Vec<KanataClientMessage>,
+        ime_status: &str,
+    ) -> Result<(), AppError> {
+        if !msgs.is_empty() {
+            self.cancel_retries();
+        }
+        for msg in msgs {
+            self.send(kanata_stream, msg, ime_status)?;
+        }
+        Ok(())
+    }
+
+    /// レイヤーのリクエストが応答待ち・再送待ちの場合はtrue。
+    fn has_layer_requests(&self) -> bool {
+        self.pending
+            .iter()
+            .chain(self.retries.iter().map(|(request, _)| request))
+            .any(|request| matches!(request.msg, KanataClientMessage::ChangeLayer { .. }))
+    }
+
+    /// kanataのレイヤーの変化が送信したレイヤーによるものかどうか。一致した送信済みのレイヤーを取り除く。
+    /// kanataは受信した順にレイヤーを変えるため、一致したものより前のレイヤーも取り除く。
+    fn take_echo(&mut self, layer_name: &str) -> bool {
+        self.echoes
+            .retain(|(_, sent_at)| sent_at.elapsed() <= REPLY_TIMEOUT);
+        match self
+            .echoes
+            .iter()
+            .position(|(layer, _)| layer == layer_name)
+        {
+            Some(index) => {
+                self.echoes.drain(..=index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 次の再送までの時間。再送するリクエストがない場合はNone。
+    fn retry_timeout(&self) -> Option<Duration> {
+        self.retries
+            .iter()
+            .map(|(_, retry_at)| retry_at.saturating_duration_since(Instant::now()))
+            .min()
+    }
+
+    /// 再送の時間になったリクエストを再送する。
+    fn retry(&mut self, kanata_stream: &mut KanataStream) -> Result<(), AppError> {
+        let now = Instant::now();
+        let (due, retries): (Vec<_>, Vec<_>) = std::mem::take(&mut self.retries)
+            .into_iter()
+            .partition(|(_, retry_at)| *retry_at <= now);
+        self.retries = retries;
+
+        for (request, _) in due {
+            info!(
+                "Retry {} for the IME \"{}\".",
+                describe_request(&request.msg),
+                request.ime_status
+            );
+            self.write(kanata_stream, request)?;
+        }
+        Ok(())
+    }
+
+    /// 古いリクエストの再送を取り消す。応答待ちのリクエストも失敗した場合に再送しない。
+    fn cancel_retries(&mut self) {
+        self.generation += 1;
+        for (request, _) in self.retries.drain(..) {
+            debug!(
+                "Canceled the retry of {} for the IME \"{}\".",
+                describe_request(&request.msg),
+                request.ime_status
+            );
+        }
+    }
+
+    /// kanataの応答を最も古いリクエストに対応付ける。失敗した場合は再送を予約する。
+    /// 新しいリクエストで置き換えられたリクエストと、現在のIMEと異なるIMEのリクエストは再送しない。
+    fn handle_reply(&mut self, result: Result<(), String>, current_ime: Option<&str>) {
+        // 応答を返さないkanataに対するリクエストを破棄する。
+        while let Some(request) = self.pending.front()
+            && request.sent_at.elapsed() > REPLY_TIMEOUT
+        {
+            debug!(
+                "No reply to {} from kanata.",
+                describe_request(&request.msg)
+            );
+            self.pending.pop_front();
+        }
+
+        let Some(mut request) = self.pending.pop_front() else {
+            match result {
+                Ok(()) => debug!("Got the reply without request."),
+                Err(msg) => warn!("Kanata returned an error: {msg}"),
+            }
+            return;
+        };
+
+        let description = describe_request(&request.msg);
+        let ime_status = &request.ime_status;
+        match result {
+            Ok(()) => match &request.msg {
+                KanataClientMessage::ReloadFile { path } => {
+                    info!("Kanata reloaded the config file: \"{path}\".");
+                }
+                _ => debug!("Kanata accepted {description}."),
+            },
+            Err(msg)
+                if request.generation != self.generation
+                    || current_ime != Some(request.ime_status.as_str()) =>
+            {
+                warn!(
+                    "Kanata rejected {description} for the IME \"{ime_status}\" ({msg}). It was superseded by a newer request."
+                );
+            }
+            Err(msg) => match request.backoff.next() {
+                Some(delay) => {
+                    warn!(
+                        "Kanata rejected {description} for the IME \"{ime_status}\" ({msg}). Retry in {delay:?}."
+                    );
+                    self.retries.push((request, Instant::now() + delay));
+                }
+                None => {
+                    error!(
+                        "Kanata rejected {description} for the IME \"{ime_status}\" ({msg}). Gave up."
+                    );
+                }
+            },
+        }
+    }
+}
+
+/// kanataから受信したメッセージを処理する。
+fn handle_kanata_message(
+    msg: KanataServerMessage,
+    requests: &mut RequestQueue,
+    current_ime: Option<&str>,
+) {
+    match msg {
+        KanataServerMessage::Response(KanataServerResponse {
+            status: KanataResponseStatus::Ok,
+            ..
+        }) => {
+            requests.handle_reply(Ok(()), current_ime);
+        }
+        KanataServerMessage::Response(KanataServerResponse {
+            status: KanataResponseStatus::Error,
+            msg,
+        }) => {
+            requests.handle_reply(Err(msg.unwrap_or_default()), current_ime);
+        }
+        KanataServerMessage::Error { msg } => {
+            requests.handle_reply(Err(msg), current_ime);
+        }
+        KanataServerMessage::LayerChange { new } => {
+            debug!("Kanata changed the layer: \"{new}\".");
+        }
+        KanataServerMessage::ConfigFileReload { new } => {
+            info!("Kanata reloaded the config file: \"{new}\".");
+        }
+        msg => {
+            debug!("Got the message from kanata: {msg:?}");
+        }
+    }
+}
+
+/// IMEの変化に対するActOnFakeKeyを作成する。前のIMEの仮想キーをreleaseし、新しいIMEの仮想キーにactionを行う。
+/// 無視するIMEの場合は前のIMEの仮想キーのままとする。
+fn fake_key_messages(
+    key_map: &ImeMap<String>,
+    action: FakeKeyAction,
+    ime_status: &str,
+    context: ImeContext,
+    acted_fake_key: &mut Option<String>,
+) -> Vec<KanataClientMessage> {
+    let new_key = key_map.get(ime_status, context);
+    if new_key == *acted_fake_key || key_map.is_ignored(ime_status, context) {
+        return Vec::new();
+    }
+
+    let mut msgs = Vec::new();
+    if let Some(pre_key) = acted_fake_key.take() {
+        msgs.push(KanataClientMessage::ActOnFakeKey {
+            name: pre_key,
+            action: FakeKeyAction::Release,
+        });
+    }
+    if let Some(new_key) = new_key {
+        msgs.push(KanataClientMessage::ActOnFakeKey {
+            name: new_key.clone(),
+            action,
+        });
+        *acted_fake_key = Some(new_key);
+    }
+    msgs
+}
+
+/// kanataのレイヤーに対応するIMEを返す。現在のIMEが既に対応している場合は切り替えない。
+/// 複数のIMEが対応する場合は名前の順で最初のものを選ぶ。パターン・アプリケーションごとに指定したIMEには切り替えない。
+fn ime_for_layer<'a>(
+    layer_map: &'a ImeMap<String>,
+    current_ime: Option<&str>,
+    context: ImeContext,
+    layer_name: &str,
+) -> Option<&'a String> {
+    if current_ime
+        .and_then(|ime| layer_map.get(ime, context))
+        .as_deref()
+        == Some(layer_name)
+    {
+        return None;
+    }
+
+    layer_map
+        .exact_entries()
+        .filter(|(_, layer)| *layer == layer_name)
+        .map(|(ime, _)| ime)
+        .min()
+}
+
+/// rememberで記憶した(フォーカス中のアプリケーション, IME)ごとの最後のレイヤー。
+/// アプリケーションごとのレイヤーを他のアプリケーションへ持ち込まないよう、アプリケーションごとに記憶する。
+type LayerMemory = HashMap<(Option<String>, String), String>;
+
+/// IMEに対応するkanataへのリクエストを作成する。
+/// 記憶したレイヤーがある場合はコマンドのレイヤーより優先する。無視するIMEの場合は記憶があっても何もしない。
+fn messages_for_ime(
+    command: &Command,
+    ime_status: &str,
+    context: ImeContext,
+    layer_memory: &LayerMemory,
+    acted_fake_key: &mut Option<String>,
+) -> Vec<KanataClientMessage> {
+    match command {
+        Command::Config(config_map) => config_map
+            .get(ime_status, context)
+            .map(|config_num| KanataClientMessage::ReloadNum { index: config_num })
+            .into_iter()
+            .collect(),
+        Command::ConfigFile(config_file_map) => config_file_map
+            .get(ime_status, context)
+            .map(|config_file| KanataClientMessage::ReloadFile { path: config_file })
+            .into_iter()
+            .collect(),
+        Command::Layer(layer_map) if layer_map.is_ignored(ime_status, context) => Vec::new(),
+        Command::Layer(layer_map) => layer_memory
+            .get(&(context.app_name.map(str::to_owned), ime_status.to_owned()))
+            .cloned()
+            .or_else(|| layer_map.get(ime_status, context))
+            .map(|layer_name| KanataClientMessage::ChangeLayer { new: layer_name })
+            .into_iter()
+            .collect(),
+        Command::FakeKey { key_map, action } => {
+            fake_key_messages(key_map, *action, ime_status, context, acted_fake_key)
+        }
+        Command::Log => Vec::new(),
+    }
+}
+
+/// 再接続・kanataの設定の再読み込みの後に現在のIMEを再度適用するリクエストを作成する。
+/// toggleは再度送ると仮想キーを反転してしまうため送らず、次のIMEでreleaseするために仮想キーだけを記録する。
+fn reapply_messages(
+    command: &Command,
+    ime_status: &str,
+    context: ImeContext,
+    layer_memory: &LayerMemory,
+    acted_fake_key: &mut Option<String>,
+) -> Vec<KanataClientMessage> {
+    if let Command::FakeKey {
+        key_map,
+        action: FakeKeyAction::Toggle,
+    } = command
+    {
+        if !key_map.is_ignored(ime_status, context) {
+            *acted_fake_key = key_map.get(ime_status, context);
+        }
+        debug!("Toggle of the virtual key is not re-applied.");
+        return Vec::new();
+    }
+
+    messages_for_ime(command, ime_status, context, layer_memory, acted_fake_key)
+}
+
+/// kanataのレイヤーの変化が自身のリクエスト・kanataの設定の再読み込みによるものかどうか。
+/// 遅れて届いた古いリクエストによる変化でIMEを切り替えると、IMEとkanataがずれるため。
+fn is_own_layer_change(
+    requests: &mut RequestQueue,
+    layer_name: &str,
+    reloaded_at: Option<Instant>,
+) -> bool {
+    requests.take_echo(layer_name)
+        || requests.has_layer_requests()
+        || reloaded_at.is_some_and(|at| at.elapsed() <= RELOAD_SETTLE_TIME)
+}
+
+/// enforceでIMEに対応するレイヤーに戻す。kanataが既にそのレイヤーにある場合は何もしない。
+fn enforce_layer(
+    requests: &mut RequestQueue,
+    kanata_stream: &mut KanataStream,
+    layer_map: &ImeMap<String>,
+    current_ime: Option<&str>,
+    context: ImeContext,
+    kanata_layer: Option<&str>,
+) -> Result<(), AppError> {
+    let Some((ime_status, layer_name)) =
+        current_ime.and_then(|ime| layer_map.get(ime, context).map(|layer| (ime, layer)))
+    else {
+        return Ok(());
+    };
+    if kanata_layer == Some(layer_name.as_str()) {
+        return Ok(());
+    }
+
+    info!("Kanata left the layer \"{layer_name}\" of the IME \"{ime_status}\". Change it back.");
+    requests.send(
+        kanata_stream,
+        KanataClientMessage::ChangeLayer {
+            new: layer_name.to_owned(),
+        },
+        ime_status,
+    )
+}
+
+/// 言語のルールがある場合のみIMEの言語を取得する。
+fn language_for<R: ImeReceiver>(command: &Command, ime_status: &str) -> Option<String> {
+    if command.has_language_rules() {
+        R::ime_language(ime_status)
+    } else {
+        None
+    }
+}
+
+fn ime_context<'a>(
+    alias: &'a Option<String>,
+    language: &'a Option<String>,
+    focused_app: &'a Option<String>,
+) -> ImeContext<'a> {
+    ImeContext {
+        alias: alias.as_deref(),
+        language: language.as_deref(),
+        app_name: focused_app.as_deref(),
+    }
+}
+
+/// kanataへリクエストを送る。current_ime・focused_appは再接続後も保持し、接続時に再度適用する。
+/// commandは設定ファイルの再読み込みで置き換わる。
+#[allow(clippy::too_many_arguments)]
+fn write_to_kanata<R: ImeReceiver>(
+    event_receiver: &EventReceiver,
+    command: &mut Arc<Command>,
+    kanata_stream: &mut KanataStream,
+    current_ime: &mut Option<String>,
+    focused_app: &mut Option<String>,
+    aliases: &ImeAliases,
+    policy: LayerPolicy,
+    switch_ime: bool,
+    fatal_error: &FatalError,
+) -> Result<(), AppError> {
+    let mut requests = RequestQueue::new();
+    // fakekeyで最後に操作した仮想キー。IMEから離れる際にreleaseする。
+    let mut acted_fake_key: Option<String> = None;
+    // レイヤーの変化に合わせて切り替えたIMEと時刻。切り替えによるIMEの変化はkanataに送り返さない。
+    let mut switched_ime: Option<(String, Instant)> = None;
+    // kanataの現在のレイヤーと、enforceでレイヤーを戻す時刻。
+    let mut kanata_layer: Option<String> = None;
+    let mut enforce_at: Option<Instant> = None;
+    // rememberで記憶したレイヤーと、kanataが設定を再読み込みした時刻。
+    let mut layer_memory = LayerMemory::new();
+    let mut reloaded_at: Option<Instant> = None;
+    // 現在のIMEの別名と言語。
+    let mut alias = current_ime
+        .as_deref()
+        .and_then(|ime_status| aliases.alias(ime_status));
+    let mut language = current_ime
+        .as_deref()
+        .and_then(|ime_status| language_for::<R>(command, ime_status));
+    // 別名から最後に検知したIME名。別名のレイヤーに合わせてIMEを切り替える際に使う。
+    let mut alias_sources: HashMap<String, String> = HashMap::new();
+    if let (Some(alias), Some(ime_status)) = (&alias, current_ime.as_deref()) {
+        alias_sources.insert(alias.to_owned(), ime_status.to_owned());
+    }
+
+    // 再接続したkanataは初期状態に戻っている可能性があるため、現在のIMEを再度適用する。
+    if let Some(ime_status) = current_ime.as_deref() {
+        info!("Re-apply the IME \"{ime_status}\" after connecting.");
+        let msgs = reapply_messages(
+            command,
+            ime_status,
+            ime_context(&alias, &language, focused_app),
+            &layer_memory,
+            &mut acted_fake_key,
+        );
+        requests.send_all(kanata_stream, msgs, ime_status)?;
+    }
+
+    while fatal_error.is_none() {
+        // 切り替えたIMEの変化が届かない場合は待つのをやめる。
+        if let Some((ime, switched_at)) = &switched_ime
+            && switched_at.elapsed() > SWITCH_ECHO_TIMEOUT
+        {
+            debug!("The IME \"{ime}\" switched by the layer change was not detected.");
+            switched_ime = None;
+        }
+
+        let timeout = [
+            requests.retry_timeout(),
+            enforce_at.map(|enforce_at| enforce_at.saturating_duration_since(Instant::now())),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+
+        let event = match timeout {
+            Some(timeout) => match event_receiver.recv_timeout(timeout) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => {
+                    requests.retry(kanata_stream)?;
+                    if let (Command::Layer(layer_map), Some(at)) = (command.as_ref(), enforce_at)
+                        && at <= Instant::now()
+                    {
+                        enforce_at = None;
+                        enforce_layer(
+                            &mut requests,
+                            kanata_stream,
+                            layer_map,
+                            current_ime.as_deref(),
+                            ime_context(&alias, &language, focused_app),
+                            kanata_layer.as_deref(),
+                        )?;
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(AppError::InnerReceiverError {
+                        receiver_name: "event_receiver".to_string(),
+                    });
+                }
+            },
+            None => event_receiver
+                .recv()
+                .map_err(|_| AppError::InnerReceiverError {
+                    receiver_name: "event_receiver".to_string(),
+                })?,
         };
 
-        if let Some(msg) = msg {
-            kanata_stream.write_all(serde_json::to_string(&msg)?.as_bytes())?;
-            info!("Sended the message to kanata.")
+        let ime_status = match event {
+            ObserverEvent::ImeStatus(ime_status) => ime_status,
+            ObserverEvent::Kanata(msg) => {
+                if let KanataServerMessage::LayerChange { new }
+                | KanataServerMessage::CurrentLayerName { name: new } = &msg
+                {
+                    kanata_layer = Some(new.to_owned());
+                }
+                if let KanataServerMessage::ConfigFileReload { .. } = &msg {
+                    reloaded_at = Some(Instant::now());
+                }
+
+                if let (Command::Layer(layer_map), KanataServerMessage::LayerChange { new }) =
+                    (command.as_ref(), &msg)
+                {
+                    match policy {
+                        // IMEをレイヤーに合わせる。
+                        LayerPolicy::Follow | LayerPolicy::Remember => {
+                            // 自身のリクエスト・設定の再読み込みによる変化では、IMEの切り替え・記憶をしない。
+                            let own_change = is_own_layer_change(&mut requests, new, reloaded_at);
+                            if own_change {
+                                debug!(
+                                    "The layer \"{new}\" was changed by the observer or a config reload."
+                                );
+                            }
+                            let switch_to = if switch_ime && R::CAN_SWITCH_IME && !own_change {
+                                ime_for_layer(
+                                    layer_map,
+                                    current_ime.as_deref(),
+                                    ime_context(&alias, &language, focused_app),
+                                    new,
+                                )
+                            } else {
+                                None
+                            };
+                            // 別名の場合は最後に検知したIME名に切り替える。
+                            let switch_to = switch_to.map(|ime| {
+                                alias_sources.get(ime).map(String::as_str).unwrap_or(ime)
+                            });
+                            // 既に切り替え先のIMEの場合は変化が届かないため切り替えない。
+                            if let Some(ime) = switch_to
+                                && current_ime.as_deref() != Some(ime)
+                            {
+                                match R::switch_ime(ime) {
+                                    Ok(()) => {
+                                        info!(
+                                            "Switched the IME to \"{ime}\" for the layer \"{new}\"."
+                                        );
+                                        switched_ime = Some((ime.to_owned(), Instant::now()));
+                                    }
+                                    Err(e) => warn!("Couldn't switch the IME to \"{ime}\": {e}"),
+                                }
+                            }
+
+                            // IMEを切り替えた場合は切り替え後のIMEのレイヤーとして記憶する。
+                            let memory_ime = switched_ime
+                                .as_ref()
+                                .map(|(ime, _)| ime.as_str())
+                                .or(current_ime.as_deref());
+                            if policy == LayerPolicy::Remember
+                                && !own_change
+                                && let Some(ime) = memory_ime
+                                && layer_map.contains(
+                                    ime,
+                                    ime_context(
+                                        &aliases.alias(ime),
+                                        &language_for::<R>(command, ime),
+                                        focused_app,
+                                    ),
+                                )
+                            {
+                                debug!("Remember the layer \"{new}\" for the IME \"{ime}\".");
+                                layer_memory
+                                    .insert((focused_app.clone(), ime.to_owned()), new.to_owned());
+                            }
+                        }
+                        // レイヤーをIMEに合わせる。猶予期間中に戻った場合は何もしない。
+                        // 遅れて届いた自身のリクエストによる変化は戻さない。
+                        LayerPolicy::Enforce { .. } if requests.take_echo(new) => {}
+                        LayerPolicy::Enforce { grace } => {
+                            let mapped_layer = current_ime.as_deref().and_then(|ime| {
+                                layer_map.get(ime, ime_context(&alias, &language, focused_app))
+                            });
+                            if mapped_layer.is_none_or(|layer| layer == *new) {
+                                enforce_at = None;
+                            } else if grace.is_zero() {
+                                enforce_layer(
+                                    &mut requests,
+                                    kanata_stream,
+                                    layer_map,
+                                    current_ime.as_deref(),
+                                    ime_context(&alias, &language, focused_app),
+                                    kanata_layer.as_deref(),
+                                )?;
+                            } else {
+                                enforce_at.get_or_insert(Instant::now() + grace);
+                            }
+                        }
+                    }
+                }
+                // 設定の再読み込みでレイヤー・仮想キーは初期状態に戻るため、現在のIMEを再度適用する。
+                // configは自身のリクエストで再読み込みするため除く。
+                if let (
+                    Command::Layer(_) | Command::FakeKey { .. },
+                    KanataServerMessage::ConfigFileReload { .. },
+                    Some(ime_status),
+                ) = (command.as_ref(), &msg, current_ime.as_deref())
+                {
+                    info!("Re-apply the IME \"{ime_status}\" after kanata reloaded the config.");
+                    acted_fake_key = None;
+                    let msgs = reapply_messages(
+                        command,
+                        ime_status,
+                        ime_context(&alias, &language, focused_app),
+                        &layer_memory,
+                        &mut acted_fake_key,
+                    );
+                    requests.send_all(kanata_stream, msgs, ime_status)?;
+                }
+                handle_kanata_message(msg, &mut requests, current_ime.as_deref());
+                continue;
+            }
+            // 古いコマンドによる状態を捨て、現在のIMEを新しいコマンドで再度適用する。
+            ObserverEvent::Reload(new_command) => {
+                *command = new_command;
+                requests.cancel_retries();
+                enforce_at = None;
+                layer_memory.clear();
+
+                let Some(ime_status) = current_ime.as_deref() else {
+                    continue;
+                };
+                language = language_for::<R>(command, ime_status);
+                info!("Re-apply the IME \"{ime_status}\" with the reloaded config.");
+                let mut msgs = Vec::new();
+                if !matches!(command.as_ref(), Command::FakeKey { .. })
+                    && let Some(name) = acted_fake_key.take()
+                {
+                    msgs.push(KanataClientMessage::ActOnFakeKey {
+                        name,
+                        action: FakeKeyAction::Release,
+                    });
+                }
+                msgs.extend(messages_for_ime(
+                    command,
+                    ime_status,
+                    ime_context(&alias, &language, focused_app),
+                    &layer_memory,
+                    &mut acted_fake_key,
+                ));
+                requests.send_all(kanata_stream, msgs, ime_status)?;
+                continue;
+            }
+            // 現在のIMEに対応する値が変わる場合のみ適用し、kanataで変えたレイヤーを保つ。
+            ObserverEvent::Focus(app_name) => {
+                let pre_app_name = std::mem::replace(focused_app, app_name);
+                let Some(ime_status) = current_ime.as_deref() else {
+                    continue;
+                };
+                if !command.changes_with(
+                    ime_status,
+                    ime_context(&alias, &language, &pre_app_name),
+                    ime_context(&alias, &language, focused_app),
+                ) {
+                    continue;
+                }
+
+                enforce_at = None;
+                let msgs = messages_for_ime(
+                    command,
+                    ime_status,
+                    ime_context(&alias, &language, focused_app),
+                    &layer_memory,
+                    &mut acted_fake_key,
+                );
+                if !msgs.is_empty() {
+                    info!(
+                        "Re-apply the IME \"{ime_status}\" for the focused application \"{}\".",
+                        focused_app.as_deref().unwrap_or_default()
+                    );
+                }
+                requests.send_all(kanata_stream, msgs, ime_status)?;
+                continue;
+            }
+            ObserverEvent::CaughtFatalError => continue,
+        };
+
+        *current_ime = Some(ime_status.clone());
+        alias = aliases.alias(&ime_status);
+        if let Some(alias) = &alias {
+            alias_sources.insert(alias.to_owned(), ime_status.clone());
         }
+        language = language_for::<R>(command, &ime_status);
+        enforce_at = None;
+        if switched_ime
+            .take()
+            .is_some_and(|(ime, _)| ime == ime_status)
+        {
+            debug!("Ignored the IME status switched by the layer change.");
+            continue;
+        }
+
+        let msgs = messages_for_ime(
+            command,
+            &ime_status,
+            ime_context(&alias, &language, focused_app),
+            &layer_memory,
+            &mut acted_fake_key,
+        );
+        requests.send_all(kanata_stream, msgs, &ime_status)?;
     }
     Err(AppError::CaughtFatalError {
         location: "write_to_kanata".to_string(),
     })
 }
 
-fn read_from_kanata(kanata_stream: TcpStream, fatal_error: &FatalError) -> Result<(), AppError> {
-    let mut kanata_read = BufReader::new(kanata_stream);
+fn read_from_kanata(
+    mut kanata_read: BufReader<KanataStream>,
+    event_sender: &EventSender,
+    fatal_error: &FatalError,
+) -> Result<(), AppError> {
     let mut buf = String::new();
 
     while fatal_error.is_none() {
@@ -75,20 +955,9 @@ fn read_from_kanata(kanata_stream: TcpStream, fatal_error: &FatalError) -> Resul
             ));
         }
 
-        if let Ok(response) = serde_json::from_str::<KanataServerResponse>(&buf) {
-            match response.status.as_str() {
-                "Ok" => {
-                    debug!("Request succeeded.");
-                }
-                "Error" => {
-                    if let Some(msg) = response.msg {
-                        debug!("Request failed.: {msg}");
-                    }
-                }
-                _ => return Err(AppError::KanataMessageError),
-            }
-        } else {
-            debug!("Got the message from kanata: {buf}");
+        match serde_json::from_str::<KanataServerMessage>(&buf) {
+            Ok(msg) => send_kanata_message(event_sender, msg, fatal_error)?,
+            Err(_) => debug!("Got the unknown message from kanata: {}", buf.trim_end()),
         }
     }
 
@@ -97,97 +966,529 @@ fn read_from_kanata(kanata_stream: TcpStream, fatal_error: &FatalError) -> Resul
     })
 }
 
-fn main() -> Result<(), AppError> {
-    use kanata_ime_observer::args::{Args, parse_args};
+/// kanataからのメッセージを読み捨てずに送る。返信が失われると要求の対応がずれるため、
+/// キューが満杯の間は空くまで待つ。待つ間もfatal_errorで停止できるようにする。
+fn send_kanata_message(
+    event_sender: &EventSender,
+    msg: KanataServerMessage,
+    fatal_error: &FatalError,
+) -> Result<(), AppError> {
+    let mut event = ObserverEvent::Kanata(msg);
+    loop {
+        match event_sender.try_send(event) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(returned)) => {
+                if !fatal_error.is_none() {
+                    return Err(AppError::CaughtFatalError {
+                        location: "read_from_kanata".to_string(),
+                    });
+                }
+                event = returned;
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(AppError::InnerSenderError {
+                    sender_name: "event_sender".to_string(),
+                });
+            }
+        }
+    }
+}
 
-    use backon::{BlockingRetryable, ExponentialBuilder};
+/// kanataにレイヤー名を問い合わせる。LayerNames以外のメッセージは読み捨てる。
+fn request_layer_names(
+    kanata_stream: &mut KanataStream,
+    kanata_read: &mut BufReader<KanataStream>,
+) -> Result<Vec<String>, AppError> {
+    write_message(kanata_stream, &KanataClientMessage::RequestLayerNames {})?;
 
-    let Args {
-        port,
-        command,
-        log_level,
-        app_config,
-    } = parse_args()?;
+    kanata_read
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(5)))?;
 
-    let command = Arc::new(command);
+    let names = (|| {
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if kanata_read.read_line(&mut buf)? == 0 {
+                return Err(AppError::CustomError(
+                    "Kanata Connection finished.".to_string(),
+                ));
+            }
 
-    simple_logger::init_with_level(log_level).map_err(|e| AppError::CustomError(e.to_string()))?;
+            match serde_json::from_str::<KanataServerMessage>(&buf) {
+                Ok(KanataServerMessage::LayerNames { names }) => return Ok(names),
+                Ok(KanataServerMessage::Error { msg }) => return Err(AppError::CustomError(msg)),
+                _ => debug!("Skipped the message from kanata: {}", buf.trim_end()),
+            }
+        }
+    })();
+
+    kanata_read.get_ref().set_read_timeout(None)?;
+
+    names
+}
+
+/// kanataのレイヤー名とコマンドのレイヤー名を比較する。strictの場合は存在しないレイヤーがあればエラーを返す。
+fn check_layer_names(
+    command: &Command,
+    kanata_stream: &mut KanataStream,
+    kanata_read: &mut BufReader<KanataStream>,
+    strict: bool,
+) -> Result<(), AppError> {
+    let Command::Layer(layer_map) = command else {
+        return Ok(());
+    };
+
+    let layer_names = match request_layer_names(kanata_stream, kanata_read) {
+        Ok(layer_names) => layer_names,
+        Err(e) => {
+            warn!("Couldn't check the layer names with kanata: {e}");
+            return Ok(());
+        }
+    };
+
+    let mut unknown_layers = layer_map
+        .fixed_values()
+        .filter(|layer_name| !layer_names.contains(layer_name))
+        .cloned()
+        .collect::<Vec<_>>();
+    unknown_layers.sort();
+    unknown_layers.dedup();
 
-    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    if unknown_layers.is_empty() {
+        info!("All layers were found in kanata.");
+        return Ok(());
+    }
+
+    for layer_name in unknown_layers.iter() {
+        error!("Kanata does not have the layer \"{layer_name}\". Kanata has {layer_names:?}.");
+    }
+
+    if strict {
+        Err(AppError::UnknownLayerError(unknown_layers))
+    } else {
+        Ok(())
+    }
+}
+
+/// strictの場合、監視を始める前に全ての接続先のレイヤー名を確認する。
+/// 存在しないレイヤーがある接続先が1つでもあればエラーを返す。接続できない接続先は接続時に確認する。
+fn check_all_layer_names(targets: &[Target]) -> Result<(), AppError> {
+    let mut unknown_layers = Vec::new();
+    for Target { address, command } in targets.iter() {
+        if !matches!(command, Command::Layer(_)) {
+            continue;
+        }
 
-    let (mut app_message_receiver, mut app_fatal_error_receiver) = initialize_app()?;
+        let result = (|| -> Result<(), AppError> {
+            let kanata_connection = address.connect(Duration::from_secs(5))?;
+            let mut writer_stream = kanata_connection.try_clone()?;
+            let mut reader_stream = BufReader::new(kanata_connection);
+            let result = check_layer_names(command, &mut writer_stream, &mut reader_stream, true);
+            let _ = writer_stream.shutdown();
+            result
+        })();
+        match result {
+            Ok(()) => {}
+            Err(AppError::UnknownLayerError(layers)) => {
+                error!("Kanata ({address}) does not have the layers {layers:?}.");
+                unknown_layers.extend(layers);
+            }
+            Err(e) => {
+                warn!("Couldn't check the layer names of kanata ({address}) before starting: {e}")
+            }
+        }
+    }
+
+    if unknown_layers.is_empty() {
+        Ok(())
+    } else {
+        unknown_layers.sort();
+        unknown_layers.dedup();
+        Err(AppError::UnknownLayerError(unknown_layers))
+    }
+}
+
+/// kanataの接続先ごとのループ。接続が切れた場合は再接続する。
+/// 再接続してもIMEのバックエンドや他の接続先には影響しない。
+#[allow(clippy::too_many_arguments)]
+fn connect_target<R: ImeReceiver>(
+    addr: KanataAddress,
+    mut command: Arc<Command>,
+    strict: bool,
+    policy: LayerPolicy,
+    switch_ime: bool,
+    aliases: Arc<ImeAliases>,
+    mut event_receiver: EventReceiver,
+    event_sender: EventSender,
+) -> Result<(), AppError> {
+    use backon::BlockingRetryable;
+
+    // 接続先ごとにFatalErrorを扱う。メッセージのレシーバーは利用しない。
+    let (context, _message_receiver, mut fatal_error_receiver) = initialize_app();
+
+    // 再接続後に適用するための現在のIMEとフォーカス中のアプリケーション。
+    let mut current_ime: Option<String> = None;
+    let mut focused_app: Option<String> = None;
 
     loop {
-        let fatal_error = initialize_fatal_error(&app_fatal_error_receiver);
+        let fatal_error = initialize_fatal_error(&fatal_error_receiver);
 
         let fatal_error_loop_handle = std::thread::spawn({
             let fatal_error = fatal_error.clone();
             move || {
-                catch_fatal_error(fatal_error, &app_fatal_error_receiver);
+                catch_fatal_error(fatal_error, &fatal_error_receiver);
 
-                app_fatal_error_receiver
+                fatal_error_receiver
             }
         });
 
-        let (writer_stream, reader_stream) = || -> Result<(TcpStream, TcpStream), AppError> {
-            let kanata_connection = TcpStream::connect_timeout(&addr, Duration::from_secs(30))?;
-
-            kanata_connection.set_write_timeout(Some(Duration::from_secs(5)))?;
+        let (mut writer_stream, reader_stream) =
+            (|| -> Result<(KanataStream, KanataStream), AppError> {
+                let kanata_connection = addr.connect(Duration::from_secs(30))?;
 
-            let writer_stream = kanata_connection.try_clone()?;
-            let reader_stream = kanata_connection;
-            Ok((writer_stream, reader_stream))
-        }
-        .retry(
-            ExponentialBuilder::default()
-                .with_min_delay(Duration::from_millis(100))
-                .with_max_delay(Duration::from_secs(10))
-                .with_max_times(10),
-        )
-        .notify(|e, duration| {
-            info!("Failed to connect to kanata: {e}");
-            info!("Retry in {duration:?}");
-        })
-        .call()?; // 失敗可能性がある．
+                kanata_connection.set_write_timeout(Some(Duration::from_secs(5)))?;
 
-        info!("Connected to kanata.");
+                let writer_stream = kanata_connection.try_clone()?;
+                let reader_stream = kanata_connection;
+                Ok((writer_stream, reader_stream))
+            })
+            .retry(
+                ExponentialBuilder::default()
+                    .with_min_delay(Duration::from_millis(100))
+                    .with_max_delay(Duration::from_secs(10))
+                    .without_max_times(),
+            )
+            .notify(|e, duration| {
+                info!("Failed to connect to kanata ({addr}): {e}");
+                info!("Retry in {duration:?}");
+            })
+            .call()?; // 接続できるまで再試行する。
 
-        let mut ime_receiver = Receiver::new(app_message_receiver, &app_config, &fatal_error)?; // 失敗可能性がある
+        info!("Connected to kanata ({addr}).");
 
-        info!("Receiver Initialized.");
+        let mut reader_stream = BufReader::new(reader_stream);
+        check_layer_names(&command, &mut writer_stream, &mut reader_stream, strict)?; // strictの場合は失敗可能性がある
 
         let write_handle = std::thread::spawn({
+            let context = context.clone();
+            let addr = addr.clone();
+            let aliases = Arc::clone(&aliases);
             let fatal_error = fatal_error.clone();
-            let command = Arc::clone(&command);
 
             move || {
-                let Err(e) =
-                    write_to_kanata(&mut ime_receiver, &command, writer_stream, &fatal_error)
-                else {
+                let mut command = command;
+                let Err(e) = write_to_kanata::<R>(
+                    &event_receiver,
+                    &mut command,
+                    &mut writer_stream,
+                    &mut current_ime,
+                    &mut focused_app,
+                    &aliases,
+                    policy,
+                    switch_ime,
+                    &fatal_error,
+                ) else {
                     unreachable!("write_to_kanata should stopped by AppError.");
                 };
 
-                error!("write_to_kanata stopped: {e}");
-                send_fatal_error(e);
+                error!("write_to_kanata ({addr}) stopped: {e}");
+                send_fatal_error(&context, e);
 
-                ime_receiver.shutdown()
+                let _ = writer_stream.shutdown(); // read_from_kanataのブロッキングを解除する。
+
+                (event_receiver, current_ime, focused_app, command)
             }
         });
 
         let read_handle = std::thread::spawn({
+            let context = context.clone();
+            let event_sender = event_sender.clone();
             let fatal_error = fatal_error.clone();
+            let addr = addr.clone();
             move || {
-                let Err(e) = read_from_kanata(reader_stream, &fatal_error) else {
+                let Err(e) = read_from_kanata(reader_stream, &event_sender, &fatal_error) else {
                     unreachable!("read_to_kanata should stopped by AppError");
                 };
 
-                error!("read_from_kanata stopped: {e}");
-                send_fatal_error(e);
+                error!("read_from_kanata ({addr}) stopped: {e}");
+                send_fatal_error(&context, e);
+            }
+        });
+
+        // 以下ハンドルの処理
+        fatal_error_receiver = fatal_error_loop_handle
+            .join()
+            .expect("catch_fatal_error panicked.");
+        handle_try_send(
+            &context,
+            &event_sender,
+            ObserverEvent::CaughtFatalError,
+            "event_sender".to_string(),
+        ); // ブロッキングしているrecvを解除する。
+        (event_receiver, current_ime, focused_app, command) =
+            write_handle.join().expect("write_to_kanata panicked.");
+        read_handle.join().expect("read_from_kanata panicked.");
+
+        std::thread::sleep(Duration::from_millis(100));
+        info!("Connection to kanata ({addr}) restarted.");
+    }
+}
+
+/// 接続先のイベントキューが満杯で送れなかったコマンドを送り直す間隔。
+const RELOAD_RETRY_SPAN: Duration = Duration::from_millis(200);
+
+/// 設定ファイルの変更・SIGHUPで設定を読み込み直し、コマンドが変わった接続先に新しいコマンドを送る。
+/// 新しい設定が不正な場合は現在の設定を維持する。接続先の追加・削除・別名の変更は再起動するまで反映しない。
+/// 接続先のイベントキューが満杯の場合はブロッキングせず、最新のコマンドを後で送り直す。
+fn reload_commands(
+    reload_receiver: Receiver<()>,
+    targets: Vec<(KanataAddress, Arc<Command>, EventSender)>,
+    aliases: Arc<ImeAliases>,
+) {
+    use kanata_ime_observer::args::parse_args;
+
+    // 接続先ごとの送信済みのコマンドと最新のコマンド。
+    let mut targets = targets
+        .into_iter()
+        .map(|(address, command, event_sender)| {
+            (address, Arc::clone(&command), command, event_sender)
+        })
+        .collect::<Vec<_>>();
+
+    loop {
+        let unsent = targets
+            .iter()
+            .any(|(_, sent, latest, _)| !Arc::ptr_eq(sent, latest));
+        let reloaded = if unsent {
+            match reload_receiver.recv_timeout(RELOAD_RETRY_SPAN) {
+                Ok(()) => true,
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        } else {
+            match reload_receiver.recv() {
+                Ok(()) => true,
+                Err(_) => return,
+            }
+        };
+
+        if reloaded {
+            // 保存時の連続した変更をまとめる。
+            std::thread::sleep(Duration::from_millis(200));
+            while reload_receiver.try_recv().is_ok() {}
+
+            let (new_targets, new_aliases) = match parse_args() {
+                Ok(args) => (args.targets, args.aliases),
+                Err(e) => {
+                    error!("Couldn't reload the config file. Keep the current config: {e}");
+                    continue;
+                }
+            };
+
+            if new_aliases != *aliases {
+                warn!("The aliases were changed in the config file. Restart to apply.");
+            }
+
+            for (address, ..) in targets.iter() {
+                if !new_targets.iter().any(|target| target.address == *address) {
+                    warn!("Kanata ({address}) was removed from the config file. Restart to apply.");
+                }
+            }
+
+            for Target { address, command } in new_targets.into_iter() {
+                let Some((_, sent, latest, _)) = targets
+                    .iter_mut()
+                    .find(|(current_address, ..)| *current_address == address)
+                else {
+                    warn!("Kanata ({address}) was added to the config file. Restart to apply.");
+                    continue;
+                };
+
+                if **latest == command {
+                    debug!("The config for kanata ({address}) was not changed.");
+                    continue;
+                }
+                *latest = if **sent == command {
+                    Arc::clone(sent)
+                } else {
+                    Arc::new(command)
+                };
+            }
+        }
+
+        for (address, sent, latest, event_sender) in targets.iter_mut() {
+            if Arc::ptr_eq(sent, latest) {
+                continue;
+            }
+            match event_sender.try_send(ObserverEvent::Reload(Arc::clone(latest))) {
+                Ok(()) => info!("Reloaded the config for kanata ({address})."),
+                Err(TrySendError::Full(_)) => {
+                    if reloaded {
+                        warn!(
+                            "The event queue of kanata ({address}) is full. Send the reloaded config later."
+                        );
+                    }
+                    continue;
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    debug!("The connection to kanata ({address}) was already stopped.");
+                }
+            }
+            *sent = Arc::clone(latest);
+        }
+    }
+}
+
+/// 選択されたバックエンドでIMEを監視し、全ての接続先のkanataへリクエストを送る。
+/// settings_pathがある場合は設定ファイルの変更を監視する。
+fn observe<R: ImeReceiver + ImeMainLoop + Send + 'static>(
+    targets: Vec<Target>,
+    strict: bool,
+    policy: LayerPolicy,
+    switch_ime: bool,
+    aliases: ImeAliases,
+    settings_path: Option<PathBuf>,
+    app_config: &R::Config,
+) -> Result<(), AppError> {
+    let aliases = Arc::new(aliases);
+    let (context, mut app_message_receiver, mut app_fatal_error_receiver) = initialize_app();
+
+    // 全ての接続先を諦めた場合にアプリケーションを終了するためのエラー。
+    let (stopped_sender, stopped_receiver) = sync_channel::<AppError>(1);
+    let (target_error_sender, target_error_receiver) = sync_channel::<AppError>(targets.len());
+
+    let has_app_rules = targets.iter().any(|target| target.command.has_app_rules());
+    let is_log = targets.iter().any(|target| target.command == Command::Log);
+
+    if strict {
+        check_all_layer_names(&targets)?; // アプリケーションを終了する。
+    }
+    if switch_ime && !R::CAN_SWITCH_IME {
+        warn!("This backend cannot switch the IME. '--switch-ime' is ignored.");
+    }
+
+    let mut event_senders = Vec::new();
+    let mut reload_targets = Vec::new();
+    for Target { address, command } in targets.into_iter() {
+        let (event_sender, event_receiver) = sync_channel(16);
+        let command = Arc::new(command);
+        event_senders.push(event_sender.clone());
+        reload_targets.push((address.clone(), Arc::clone(&command), event_sender.clone()));
+
+        std::thread::spawn({
+            let context = context.clone();
+            let stopped_sender = stopped_sender.clone();
+            let target_error_sender = target_error_sender.clone();
+            let aliases = Arc::clone(&aliases);
+            move || {
+                let Err(e) = connect_target::<R>(
+                    address.clone(),
+                    command,
+                    strict,
+                    policy,
+                    switch_ime,
+                    aliases,
+                    event_receiver,
+                    event_sender,
+                ) else {
+                    unreachable!("connect_target should stopped by AppError.");
+                };
+
+                // strictで存在しないレイヤーがある場合は、他の接続先も含めてアプリケーションを終了する。
+                if let AppError::UnknownLayerError(_) = e {
+                    error!("Kanata ({address}) does not have the layers. Stop all connections.");
+                    let _ = stopped_sender.try_send(e);
+                    send_fatal_error(
+                        &context,
+                        AppError::CustomError("Kanata does not have the layers.".to_string()),
+                    );
+                    return;
+                }
+
+                error!("Gave up the connection to kanata ({address}): {e}");
+                let _ = target_error_sender.send(e);
+            }
+        });
+    }
+    drop(target_error_sender);
+
+    if has_app_rules || is_log {
+        forward_focus(event_senders.clone(), has_app_rules);
+    }
+    let latest_imes = event_senders
+        .into_iter()
+        .map(|event_sender| LatestEvent::spawn(event_sender, ObserverEvent::ImeStatus))
+        .collect::<Vec<_>>();
+
+    if let Some(settings_path) = settings_path {
+        let (reload_sender, reload_receiver) = sync_channel(1);
+        watch_settings(settings_path, reload_sender);
+        let aliases = Arc::clone(&aliases);
+        std::thread::spawn(move || reload_commands(reload_receiver, reload_targets, aliases));
+    }
+
+    std::thread::spawn({
+        let context = context.clone();
+        move || {
+            // 全ての接続先のスレッドが終了するとイテレーターが終了する。
+            if let Some(e) = target_error_receiver.iter().last() {
+                let _ = stopped_sender.send(e);
+                send_fatal_error(
+                    &context,
+                    AppError::CustomError("All connections to kanata stopped.".to_string()),
+                );
+            }
+        }
+    });
+
+    loop {
+        let fatal_error = initialize_fatal_error(&app_fatal_error_receiver);
+
+        if let Ok(e) = stopped_receiver.try_recv() {
+            return Err(e); // アプリケーションを終了する。
+        }
+
+        let fatal_error_loop_handle = std::thread::spawn({
+            let fatal_error = fatal_error.clone();
+            move || {
+                catch_fatal_error(fatal_error, &app_fatal_error_receiver);
+
+                app_fatal_error_receiver
+            }
+        });
+
+        let mut ime_receiver = R::new(&context, app_message_receiver, app_config, &fatal_error)?; // 失敗可能性がある
+
+        info!("Receiver Initialized.");
+
+        let forward_handle = std::thread::spawn({
+            let context = context.clone();
+            let latest_imes = latest_imes.clone();
+            let aliases = Arc::clone(&aliases);
+            let fatal_error = fatal_error.clone();
+
+            move || {
+                let Err(e) = forward_ime_status(
+                    &mut ime_receiver,
+                    &latest_imes,
+                    &aliases,
+                    is_log,
+                    &fatal_error,
+                ) else {
+                    unreachable!("forward_ime_status should stopped by AppError.");
+                };
+
+                error!("forward_ime_status stopped: {e}");
+                send_fatal_error(&context, e);
+
+                ime_receiver.shutdown()
             }
         });
 
         // 以下メインスレッドの処理
-        let Err(e) = main_loop(&fatal_error) else {
+        let Err(e) = R::main_loop(&context, app_config, &fatal_error) else {
             unreachable!("main loop should stopped by AppError.");
         };
         if let AppError::CaughtFatalError { .. } = e {
@@ -199,11 +1500,468 @@ fn main() -> Result<(), AppError> {
         app_fatal_error_receiver = fatal_error_loop_handle
             .join()
             .expect("catch_fatal_error panicked.");
-        send_message(Message::CaughtFatalError); // ブロッキングしているrecvを解除する。
-        app_message_receiver = write_handle.join().expect("write_to_kanata panicked.");
-        read_handle.join().expect("read_from_kanata panicked.");
+        send_message(&context, Message::CaughtFatalError); // ブロッキングしているrecvを解除する。
+        app_message_receiver = forward_handle.join().expect("forward_ime_status panicked.");
+
+        if let Ok(e) = stopped_receiver.try_recv() {
+            return Err(e); // アプリケーションを終了する。
+        }
 
         std::thread::sleep(Duration::from_millis(100));
         info!("Main loop restarted.");
     }
 }
+
+fn main() -> Result<(), AppError> {
+    use kanata_ime_observer::args::{Args, parse_args};
+
+    let Args {
+        targets,
+        settings_path,
+        log_level,
+        strict,
+        policy,
+        switch_ime,
+        backend,
+        script_config,
+        aliases,
+        #[cfg(target_os = "linux")]
+        ibus_config,
+        #[cfg(target_os = "linux")]
+        fcitx_config,
+        #[cfg(not(target_os = "linux"))]
+        app_config,
+    } = parse_args()?;
+
+    simple_logger::init_with_level(log_level).map_err(|e| AppError::CustomError(e.to_string()))?;
+
+    match backend.resolve()? {
+        #[cfg(target_os = "linux")]
+        Backend::Ibus => observe::<IbusImeReceiver>(
+            targets,
+            strict,
+            policy,
+            switch_ime,
+            aliases,
+            settings_path,
+            &ibus_config,
+        ),
+        #[cfg(target_os = "linux")]
+        Backend::Fcitx => observe::<FcitxImeReceiver>(
+            targets,
+            strict,
+            policy,
+            switch_ime,
+            aliases,
+            settings_path,
+            &fcitx_config,
+        ),
+        #[cfg(not(target_os = "linux"))]
+        Backend::Native => observe::<Receiver>(
+            targets,
+            strict,
+            policy,
+            switch_ime,
+            aliases,
+            settings_path,
+            &app_config,
+        ),
+        Backend::Script => match observe::<ScriptImeReceiver>(
+            targets,
+            strict,
+            policy,
+            switch_ime,
+            aliases,
+            settings_path,
+            &script_config,
+        ) {
+            Err(AppError::ScriptEnd) => {
+                info!("Reached the end of the script. Exit.");
+                Ok(())
+            }
+            result => result,
+        },
+        Backend::Auto => unreachable!("Backend::resolve never returns Backend::Auto."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kanata_ime_observer::transport::KanataTransport;
+
+    use std::io::{self, Read};
+
+    /// 書き込まれたメッセージを記録するkanataとの接続。
+    #[derive(Clone, Default)]
+    struct RecordingStream(Arc<Mutex<Vec<u8>>>);
+
+    impl RecordingStream {
+        fn messages(&self) -> Vec<KanataClientMessage> {
+            let buf = self.0.lock().unwrap();
+            buf.lines()
+                .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+                .collect()
+        }
+    }
+
+    impl Read for RecordingStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for RecordingStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl KanataTransport for RecordingStream {
+        fn try_clone(&self) -> io::Result<KanataStream> {
+            Ok(Box::new(self.clone()))
+        }
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+        fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+        fn shutdown(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn change_layer(new: &str) -> KanataClientMessage {
+        KanataClientMessage::ChangeLayer {
+            new: new.to_string(),
+        }
+    }
+
+    #[test]
+    fn replies_match_the_oldest_request() {
+        let recording = RecordingStream::default();
+        let mut kanata_stream: KanataStream = Box::new(recording.clone());
+        let mut requests = RequestQueue::new();
+
+        requests
+            .send_all(
+                &mut kanata_stream,
+                vec![change_layer("base"), change_layer("japanese")],
+                "mozc",
+            )
+            .unwrap();
+        assert_eq!(
+            recording.messages(),
+            [change_layer("base"), change_layer("japanese")]
+        );
+
+        requests.handle_reply(Ok(()), Some("mozc"));
+        assert_eq!(requests.pending.len(), 1);
+        assert_eq!(requests.pending[0].msg, change_layer("japanese"));
+
+        requests.handle_reply(Err("layer not found".to_string()), Some("mozc"));
+        assert!(requests.pending.is_empty());
+        assert_eq!(requests.retries.len(), 1);
+        assert_eq!(requests.retries[0].0.msg, change_layer("japanese"));
+    }
+
+    #[test]
+    fn toggle_is_not_reapplied() {
+        let key_map = ImeMap::new([("mozc".to_string(), "ime-ja".to_string())]).unwrap();
+        let toggle = Command::FakeKey {
+            key_map: key_map.clone(),
+            action: FakeKeyAction::Toggle,
+        };
+        let mut acted_fake_key = None;
+        let msgs = reapply_messages(
+            &toggle,
+            "mozc",
+            ImeContext::default(),
+            &LayerMemory::new(),
+            &mut acted_fake_key,
+        );
+        assert!(msgs.is_empty());
+        // 次のIMEでreleaseする。
+        assert_eq!(acted_fake_key.as_deref(), Some("ime-ja"));
+        assert_eq!(
+            messages_for_ime(
+                &toggle,
+                "xkb:us::eng",
+                ImeContext::default(),
+                &LayerMemory::new(),
+                &mut acted_fake_key,
+            ),
+            [KanataClientMessage::ActOnFakeKey {
+                name: "ime-ja".to_string(),
+                action: FakeKeyAction::Release,
+            }]
+        );
+
+        let press = Command::FakeKey {
+            key_map,
+            action: FakeKeyAction::Press,
+        };
+        let mut acted_fake_key = None;
+        assert_eq!(
+            reapply_messages(
+                &press,
+                "mozc",
+                ImeContext::default(),
+                &LayerMemory::new(),
+                &mut acted_fake_key,
+            ),
+            [KanataClientMessage::ActOnFakeKey {
+                name: "ime-ja".to_string(),
+                action: FakeKeyAction::Press,
+            }]
+        );
+    }
+
+    #[test]
+    fn remembered_layers_follow_ignored_and_app_rules() {
+        let layer_map = ImeMap::new([("mozc".to_string(), "oyayubi".to_string())])
+            .unwrap()
+            .with_app(
+                "kitty".to_string(),
+                ImeMap::new([("mozc".to_string(), "oyayubi-term".to_string())]).unwrap(),
+            )
+            .unwrap()
+            .with_app(
+                "steam_app_*".to_string(),
+                ImeMap::new([])
+                    .unwrap()
+                    .with_ignored(["*".to_string()])
+                    .unwrap(),
+            )
+            .unwrap();
+        let command = Command::Layer(layer_map);
+        let app = |app_name| ImeContext {
+            app_name: Some(app_name),
+            ..Default::default()
+        };
+        let mut layer_memory = LayerMemory::from([
+            ((None, "mozc".to_string()), "nicola".to_string()),
+            (
+                (Some("steam_app_1234".to_string()), "mozc".to_string()),
+                "nicola".to_string(),
+            ),
+        ]);
+
+        // 無視するアプリケーションでは記憶があっても何もしない。
+        assert!(
+            messages_for_ime(
+                &command,
+                "mozc",
+                app("steam_app_1234"),
+                &layer_memory,
+                &mut None
+            )
+            .is_empty()
+        );
+        // 他のアプリケーションで記憶したレイヤーはアプリケーションごとのレイヤーより優先しない。
+        assert_eq!(
+            messages_for_ime(&command, "mozc", app("kitty"), &layer_memory, &mut None),
+            [change_layer("oyayubi-term")]
+        );
+        assert_eq!(
+            messages_for_ime(
+                &command,
+                "mozc",
+                ImeContext::default(),
+                &layer_memory,
+                &mut None
+            ),
+            [change_layer("nicola")]
+        );
+
+        layer_memory.insert(
+            (Some("kitty".to_string()), "mozc".to_string()),
+            "nicola-term".to_string(),
+        );
+        assert_eq!(
+            messages_for_ime(&command, "mozc", app("kitty"), &layer_memory, &mut None),
+            [change_layer("nicola-term")]
+        );
+    }
+
+    #[test]
+    fn layer_requests_are_found_until_replied() {
+        let mut kanata_stream: KanataStream = Box::<RecordingStream>::default();
+        let mut requests = RequestQueue::new();
+
+        requests
+            .send_all(&mut kanata_stream, vec![change_layer("japanese")], "mozc")
+            .unwrap();
+        assert!(requests.has_layer_requests());
+
+        requests.handle_reply(Err("layer not found".to_string()), Some("mozc"));
+        assert!(requests.has_layer_requests());
+
+        requests.cancel_retries();
+        assert!(!requests.has_layer_requests());
+    }
+
+    #[test]
+    fn stale_echoes_are_own_layer_changes() {
+        let mut kanata_stream: KanataStream = Box::<RecordingStream>::default();
+        let mut requests = RequestQueue::new();
+
+        // mozc→us→mozcと素早く切り替え、kanataのレイヤーの変化が応答の後に届く。
+        for (ime_status, layer_name) in [("mozc", "jp"), ("xkb:us::eng", "base"), ("mozc", "jp")] {
+            requests
+                .send_all(
+                    &mut kanata_stream,
+                    vec![change_layer(layer_name)],
+                    ime_status,
+                )
+                .unwrap();
+        }
+        for _ in 0..3 {
+            requests.handle_reply(Ok(()), Some("mozc"));
+        }
+        assert!(is_own_layer_change(&mut requests, "jp", None));
+        assert!(is_own_layer_change(&mut requests, "base", None));
+        assert!(is_own_layer_change(&mut requests, "jp", None));
+
+        // 以降の変化はkanataで行ったもの。設定の再読み込みの直後は除く。
+        assert!(!is_own_layer_change(&mut requests, "base", None));
+        assert!(is_own_layer_change(
+            &mut requests,
+            "base",
+            Some(Instant::now())
+        ));
+
+        // 応答待ちのリクエストがある間の変化も除く。
+        requests
+            .send_all(&mut kanata_stream, vec![change_layer("jp")], "mozc")
+            .unwrap();
+        assert!(is_own_layer_change(&mut requests, "base", None));
+    }
+
+    #[test]
+    fn rejected_requests_are_retried_with_backoff() {
+        let recording = RecordingStream::default();
+        let mut kanata_stream: KanataStream = Box::new(recording.clone());
+        let mut requests = RequestQueue::new();
+        assert_eq!(requests.retry_timeout(), None);
+
+        requests
+            .send_all(&mut kanata_stream, vec![change_layer("japanese")], "mozc")
+            .unwrap();
+        requests.handle_reply(Err("layer not found".to_string()), Some("mozc"));
+        let timeout = requests.retry_timeout().unwrap();
+        assert!(timeout > Duration::ZERO && timeout <= Duration::from_millis(200));
+
+        // 時間前には再送しない。
+        requests.retry(&mut kanata_stream).unwrap();
+        assert_eq!(recording.messages().len(), 1);
+
+        requests.retries[0].1 = Instant::now();
+        requests.retry(&mut kanata_stream).unwrap();
+        assert_eq!(
+            recording.messages(),
+            [change_layer("japanese"), change_layer("japanese")]
+        );
+        assert!(requests.retries.is_empty());
+        assert_eq!(requests.pending.len(), 1);
+
+        // 2回目の失敗は1回目より長く待つ。
+        requests.handle_reply(Err("layer not found".to_string()), Some("mozc"));
+        assert!(requests.retry_timeout().unwrap() > timeout);
+    }
+
+    #[test]
+    fn superseded_requests_are_not_retried() {
+        let recording = RecordingStream::default();
+        let mut kanata_stream: KanataStream = Box::new(recording.clone());
+        let mut requests = RequestQueue::new();
+
+        requests
+            .send_all(&mut kanata_stream, vec![change_layer("japanese")], "mozc")
+            .unwrap();
+        requests
+            .send_all(
+                &mut kanata_stream,
+                vec![change_layer("base")],
+                "xkb:us::eng",
+            )
+            .unwrap();
+        requests.handle_reply(Err("layer not found".to_string()), Some("xkb:us::eng"));
+        assert!(requests.retries.is_empty());
+
+        // 置き換えていない最新のリクエストは再送する。
+        requests.handle_reply(Err("layer not found".to_string()), Some("xkb:us::eng"));
+        assert_eq!(requests.retries.len(), 1);
+        assert_eq!(requests.retries[0].0.msg, change_layer("base"));
+
+        // リクエストのないIMEに変わった場合も再送しない。
+        let mut requests = RequestQueue::new();
+        requests
+            .send_all(&mut kanata_stream, vec![change_layer("japanese")], "mozc")
+            .unwrap();
+        requests.handle_reply(Err("layer not found".to_string()), Some("anthy"));
+        assert!(requests.retries.is_empty());
+    }
+
+    #[test]
+    fn kanata_replies_wait_for_the_full_queue() {
+        let (event_sender, event_receiver) = sync_channel(1);
+        let fatal_error = FatalError::default();
+        event_sender
+            .send(ObserverEvent::ImeStatus("mozc".to_string()))
+            .unwrap();
+
+        let handle = std::thread::spawn(move || {
+            send_kanata_message(
+                &event_sender,
+                KanataServerMessage::LayerChange {
+                    new: "jp".to_string(),
+                },
+                &fatal_error,
+            )
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(matches!(
+            event_receiver.recv().unwrap(),
+            ObserverEvent::ImeStatus(_)
+        ));
+        assert!(matches!(
+            event_receiver.recv().unwrap(),
+            ObserverEvent::Kanata(KanataServerMessage::LayerChange { .. })
+        ));
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn latest_events_are_coalesced_and_not_dropped() {
+        let (event_sender, event_receiver) = sync_channel(1);
+        event_sender
+            .send(ObserverEvent::ImeStatus("xkb:us::eng".to_string()))
+            .unwrap();
+
+        // キューが満杯の間の変化は最新の値にまとめる。
+        let latest_ime = Arc::new(LatestEvent::new());
+        latest_ime.set("mozc-jp".to_string());
+        latest_ime.set("anthy".to_string());
+        std::thread::spawn({
+            let latest_ime = Arc::clone(&latest_ime);
+            move || latest_ime.forward(&event_sender, ObserverEvent::ImeStatus)
+        });
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            if let ObserverEvent::ImeStatus(ime_status) = event_receiver.recv().unwrap() {
+                received.push(ime_status);
+            }
+        }
+        latest_ime.set("xkb:us::eng".to_string());
+        if let ObserverEvent::ImeStatus(ime_status) = event_receiver.recv().unwrap() {
+            received.push(ime_status);
+        }
+        assert_eq!(received, ["xkb:us::eng", "anthy", "xkb:us::eng"]);
+    }
+}
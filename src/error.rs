@@ -15,6 +15,11 @@ pub enum AppError {
     #[error("DbusParseError: {0}")]
     DbusParseError(String),
 
+    /// フォーカス中のウィンドウの取得(X11・sway・Hyprland)に関するエラー。
+    #[cfg(target_os = "linux")]
+    #[error("FocusError: {0}")]
+    FocusError(String),
+
     /// WindowsAPIに関するエラー。
     #[cfg(target_os = "windows")]
     #[error("WinApiError: {0}")]
@@ -49,10 +54,22 @@ pub enum AppError {
     #[error("ArgError: {0}")]
     ArgError(String),
 
+    /// 設定ファイルに関するエラー。
+    #[error("SettingsError: {0}")]
+    SettingsError(String),
+
+    /// kanataに存在しないレイヤー名が指定された際のエラー。
+    #[error("UnknownLayerError: kanata does not have the layers {0:?}.")]
+    UnknownLayerError(Vec<String>),
+
     /// 未知のkanataメッセージに関するエラー。
     #[error("KanataMessageError")]
     KanataMessageError,
 
+    /// scriptの入力元の末尾に達した際のエラー。アプリを正常に終了する。
+    #[error("ScriptEnd: reached the end of the script.")]
+    ScriptEnd,
+
     /// FatalErrorを検知してループを終了するエラー。基本的にログが残りループが終了し、再接続を試みる。
     #[error("{location} was stopped by fatal error.")]
     CaughtFatalError { location: String },
@@ -1,16 +1,46 @@
 use crate::{
-    AppError, FatalError, InnerReceiver, Message, MessageReceiver, handle_try_send,
-    send_fatal_error, send_message,
+    AppContext, AppError, FatalError, ImeMainLoop, ImeReceiver, InnerReceiver, Message,
+    MessageReceiver, handle_try_send, send_fatal_error, send_message,
 };
 
 use dbus::blocking::SyncConnection;
 use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
 use dbus::message::MatchRule;
-use log::{debug, info};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
 
-use std::{sync::mpsc::sync_channel, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, mpsc::sync_channel},
+    time::Duration,
+};
+
+/// 入力メソッド名から言語コードへのキャッシュ。
+static INPUT_METHOD_LANGUAGES: Lazy<Mutex<HashMap<String, Option<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
-pub fn dbus_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
+/// 利用可能な入力メソッドの(名前, 表示名, ネイティブ名, アイコン, ラベル, 言語コード, 設定可能か)。
+type InputMethodEntry = (String, String, String, String, String, String, bool);
+
+/// 利用可能な入力メソッドの言語コードを取得する。言語コードが空の場合はNoneとする。
+fn get_input_method_languages() -> Result<HashMap<String, Option<String>>, AppError> {
+    let conn = SyncConnection::new_session()?;
+    let proxy = conn.with_proxy(
+        "org.fcitx.Fcitx5",
+        "/controller",
+        Duration::from_millis(500),
+    );
+    let (entries,): (Vec<InputMethodEntry>,) =
+        proxy.method_call("org.fcitx.Fcitx.Controller1", "AvailableInputMethods", ())?;
+
+    Ok(entries
+        .into_iter()
+        .map(|(name, _, _, _, _, language, _)| (name, (!language.is_empty()).then_some(language)))
+        .collect())
+}
+
+pub fn dbus_main_loop(context: &AppContext, fatal_error: &FatalError) -> Result<(), AppError> {
     let conn = SyncConnection::new_session()?;
     info!("Connected to 'session bus.'");
 
@@ -67,13 +97,18 @@ pub fn dbus_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
     let token = fcitx5_sni_proxy.match_start(
         signal_ml,
         true,
-        Box::new(move |_message, _| {
-            send_message(Message::GetImeStatus);
+        Box::new({
+            let context = context.clone();
+            move |_message, _| {
+                send_message(&context, Message::GetImeStatus);
 
-            true
+                true
+            }
         }),
     )?;
 
+    send_message(context, Message::GetImeStatus); // 起動時の状態を取得する。
+
     // メインループ
     while fatal_error.is_none() {
         conn.process(Duration::from_millis(1000))?;
@@ -87,7 +122,8 @@ pub fn dbus_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
     })
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FcitxImeReceiverConfig {}
 
 #[allow(clippy::derivable_impls)]
@@ -97,13 +133,17 @@ impl Default for FcitxImeReceiverConfig {
     }
 }
 pub struct FcitxImeReceiver {
+    context: AppContext,
     _worker_handle: std::thread::JoinHandle<MessageReceiver>,
     inner_receiver: InnerReceiver,
     pre_ime_status: Option<String>,
 }
 
-impl FcitxImeReceiver {
-    pub fn new(
+impl ImeReceiver for FcitxImeReceiver {
+    type Config = FcitxImeReceiverConfig;
+
+    fn new(
+        context: &AppContext,
         message_receiver: MessageReceiver,
         config: &FcitxImeReceiverConfig,
         fatal_error: &FatalError,
@@ -116,6 +156,7 @@ impl FcitxImeReceiver {
         let (inner_sender, inner_receiver) = sync_channel(1);
 
         let _worker_handle = std::thread::spawn({
+            let context = context.clone();
             let fatal_error = fatal_error.clone();
 
             move || {
@@ -135,13 +176,14 @@ impl FcitxImeReceiver {
                     ) {
                         Ok((ime_engine,)) => {
                             handle_try_send(
+                                &context,
                                 &inner_sender,
                                 ime_engine,
                                 "FcitxImeReceiver inner sender".to_string(),
                             );
                         }
                         Err(dbus_err) => {
-                            send_fatal_error(dbus_err.into());
+                            send_fatal_error(&context, dbus_err.into());
                         }
                     }
                 }
@@ -151,12 +193,13 @@ impl FcitxImeReceiver {
         });
 
         Ok(Self {
+            context: context.clone(),
             _worker_handle,
             inner_receiver,
             pre_ime_status: None,
         })
     }
-    pub fn receive(&mut self) -> Result<String, AppError> {
+    fn receive(&mut self) -> Result<String, AppError> {
         loop {
             let new_ime_status =
                 self.inner_receiver
@@ -176,11 +219,63 @@ impl FcitxImeReceiver {
             }
         }
     }
-    pub fn shutdown(self) -> MessageReceiver {
-        send_fatal_error(AppError::CustomError("Receiver shutdown.".to_string()));
+    fn shutdown(self) -> MessageReceiver {
+        send_fatal_error(
+            &self.context,
+            AppError::CustomError("Receiver shutdown.".to_string()),
+        );
         let message_receiver = self._worker_handle.join().expect("worker thread panicked.");
         debug!("FcitxImeReceiver shutdown.");
 
         message_receiver
     }
+    const CAN_SWITCH_IME: bool = true;
+
+    fn switch_ime(ime_status: &str) -> Result<(), AppError> {
+        let conn = SyncConnection::new_session()?;
+        let proxy = conn.with_proxy(
+            "org.fcitx.Fcitx5",
+            "/controller",
+            Duration::from_millis(500),
+        );
+        proxy.method_call::<(), _, _, _>(
+            "org.fcitx.Fcitx.Controller1",
+            "SetCurrentIM",
+            (ime_status,),
+        )?;
+        Ok(())
+    }
+
+    fn ime_language(ime_status: &str) -> Option<String> {
+        let mut languages = INPUT_METHOD_LANGUAGES
+            .lock()
+            .expect("input method languages poisoned.");
+        if let Some(language) = languages.get(ime_status) {
+            return language.clone();
+        }
+
+        // 未知の入力メソッドの場合は一覧を取得し直す。
+        match get_input_method_languages() {
+            Ok(new_languages) => {
+                *languages = new_languages;
+                let language = languages.entry(ime_status.to_string()).or_default().clone();
+                debug!("Language of the input method '{ime_status}': {language:?}");
+                language
+            }
+            Err(e) => {
+                warn!("Couldn't get the language of the input method '{ime_status}': {e}");
+                None
+            }
+        }
+    }
+}
+
+impl ImeMainLoop for FcitxImeReceiver {
+    fn main_loop(
+        context: &AppContext,
+        _config: &Self::Config,
+        fatal_error: &FatalError,
+    ) -> Result<(), AppError> {
+        dbus_main_loop(context, fatal_error)
+    }
 }
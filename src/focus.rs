@@ -0,0 +1,463 @@
+use crate::AppError;
+
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde_json::Value;
+
+/// 接続が切れた場合に再接続するまでの時間。
+const RECONNECT_SPAN: Duration = Duration::from_secs(5);
+
+/// フォーカス中のアプリケーションを取得する方法。アプリケーション名はapp idまたはclassとする。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FocusProvider {
+    /// ルートウィンドウの`_NET_ACTIVE_WINDOW`が指すウィンドウの`WM_CLASS`のclass。
+    X11,
+    /// swayのIPCソケット。`app_id`、XWaylandのウィンドウでは`class`。
+    Sway(PathBuf),
+    /// HyprlandのIPCソケットのディレクトリ。`class`。
+    Hyprland(PathBuf),
+}
+
+impl FocusProvider {
+    /// 環境変数から選ぶ。sway・Hyprland・X11の順に試す。
+    pub fn detect() -> Option<Self> {
+        if let Some(path) = std::env::var_os("SWAYSOCK") {
+            return Some(FocusProvider::Sway(PathBuf::from(path)));
+        }
+
+        if let Some(signature) = std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE") {
+            // 古いHyprlandは/tmp/hyprを利用する。
+            let dir = std::env::var_os("XDG_RUNTIME_DIR")
+                .map(|runtime_dir| PathBuf::from(runtime_dir).join("hypr").join(&signature))
+                .filter(|dir| dir.exists())
+                .unwrap_or_else(|| PathBuf::from("/tmp/hypr").join(&signature));
+            return Some(FocusProvider::Hyprland(dir));
+        }
+
+        if std::env::var_os("DISPLAY").is_some() {
+            return Some(FocusProvider::X11);
+        }
+
+        None
+    }
+
+    /// 現在のアプリケーションとフォーカスの変化をon_focusに送る。接続が切れた場合はエラーを返す。
+    pub fn watch(&self, on_focus: &mut dyn FnMut(Option<String>)) -> Result<(), AppError> {
+        match self {
+            FocusProvider::X11 => watch_x11(on_focus),
+            FocusProvider::Sway(path) => watch_sway(path, on_focus),
+            FocusProvider::Hyprland(dir) => watch_hyprland(dir, on_focus),
+        }
+    }
+}
+
+impl Display for FocusProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FocusProvider::X11 => write!(f, "X11"),
+            FocusProvider::Sway(path) => write!(f, "sway ({})", path.display()),
+            FocusProvider::Hyprland(dir) => write!(f, "Hyprland ({})", dir.display()),
+        }
+    }
+}
+
+/// フォーカス中のアプリケーションを監視し、変化した場合にon_focusを呼ぶ。接続が切れた場合は再接続する。
+pub fn watch_focus(
+    provider: FocusProvider,
+    mut on_focus: impl FnMut(Option<String>) + Send + 'static,
+) {
+    std::thread::spawn(move || {
+        debug!("Watching the focused window with {provider}.");
+
+        // 同じアプリケーションのウィンドウ間の移動は通知しない。
+        let mut focused_app: Option<Option<String>> = None;
+        loop {
+            let result = provider.watch(&mut |app| {
+                if focused_app.as_ref() != Some(&app) {
+                    focused_app = Some(app.clone());
+                    on_focus(app);
+                }
+            });
+            if let Err(e) = result {
+                warn!("Lost the focused window ({provider}): {e}. Retry in {RECONNECT_SPAN:?}.");
+            }
+            std::thread::sleep(RECONNECT_SPAN);
+        }
+    });
+}
+
+fn x11_error(e: impl Display) -> AppError {
+    AppError::FocusError(format!("X11: {e}"))
+}
+
+fn watch_x11(on_focus: &mut dyn FnMut(Option<String>)) -> Result<(), AppError> {
+    use x11rb::{
+        connection::Connection,
+        protocol::{
+            Event,
+            xproto::{ChangeWindowAttributesAux, ConnectionExt, EventMask},
+        },
+    };
+
+    let (conn, screen_num) = x11rb::connect(None).map_err(x11_error)?;
+    let root = conn.setup().roots[screen_num].root;
+    let net_active_window = conn
+        .intern_atom(false, b"_NET_ACTIVE_WINDOW")
+        .map_err(x11_error)?
+        .reply()
+        .map_err(x11_error)?
+        .atom;
+    conn.change_window_attributes(
+        root,
+        &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+    )
+    .map_err(x11_error)?
+    .check()
+    .map_err(x11_error)?;
+
+    loop {
+        on_focus(x11_active_app(&conn, root, net_active_window)?);
+
+        // _NET_ACTIVE_WINDOWが変わるまで待つ。
+        loop {
+            if let Event::PropertyNotify(event) = conn.wait_for_event().map_err(x11_error)?
+                && event.atom == net_active_window
+            {
+                break;
+            }
+        }
+    }
+}
+
+/// アクティブなウィンドウのclass。ウィンドウがない・閉じた場合はNoneを返す。
+fn x11_active_app(
+    conn: &x11rb::rust_connection::RustConnection,
+    root: u32,
+    net_active_window: u32,
+) -> Result<Option<String>, AppError> {
+    use x11rb::{
+        errors::ReplyError,
+        protocol::xproto::{AtomEnum, ConnectionExt},
+    };
+
+    let reply = conn
+        .get_property(false, root, net_active_window, AtomEnum::WINDOW, 0, 1)
+        .map_err(x11_error)?
+        .reply()
+        .map_err(x11_error)?;
+    let Some(window) = reply
+        .value32()
+        .and_then(|mut windows| windows.next())
+        .filter(|window| *window != 0)
+    else {
+        return Ok(None);
+    };
+
+    let reply = match conn
+        .get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 1024)
+        .map_err(x11_error)?
+        .reply()
+    {
+        Ok(reply) => reply,
+        Err(ReplyError::X11Error(e)) => {
+            debug!("Couldn't get WM_CLASS of the active window: {e:?}");
+            return Ok(None);
+        }
+        Err(e) => return Err(x11_error(e)),
+    };
+
+    // WM_CLASSはinstanceとclassをNUL区切りで持つ。
+    Ok(reply
+        .value
+        .split(|byte| *byte == 0)
+        .rfind(|name| !name.is_empty())
+        .map(|class| String::from_utf8_lossy(class).into_owned()))
+}
+
+/// swayのIPC(i3互換)のメッセージの先頭。
+const SWAY_MAGIC: &[u8] = b"i3-ipc";
+const SWAY_SUBSCRIBE: u32 = 2;
+const SWAY_GET_TREE: u32 = 4;
+
+/// swayのIPCのメッセージ。マジック・ペイロード長・種類・ペイロードの順でネイティブエンディアンとする。
+fn write_sway_message(
+    stream: &mut impl Write,
+    message_type: u32,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let mut buf = SWAY_MAGIC.to_vec();
+    buf.extend((payload.len() as u32).to_ne_bytes());
+    buf.extend(message_type.to_ne_bytes());
+    buf.extend(payload);
+    stream.write_all(&buf)
+}
+
+fn read_sway_message(stream: &mut impl Read) -> Result<(u32, Vec<u8>), AppError> {
+    let mut header = [0; 14];
+    stream.read_exact(&mut header)?;
+    if &header[..6] != SWAY_MAGIC {
+        return Err(AppError::FocusError(
+            "sway: Invalid IPC message.".to_string(),
+        ));
+    }
+
+    let length = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+    let message_type = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+    let mut payload = vec![0; length as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((message_type, payload))
+}
+
+/// swayのノードのアプリケーション名。
+fn sway_app_name(node: &Value) -> Option<String> {
+    node.get("app_id")
+        .and_then(Value::as_str)
+        .or_else(|| node.pointer("/window_properties/class")?.as_str())
+        .map(str::to_string)
+}
+
+/// swayのツリーからフォーカス中のノードを探す。
+fn sway_focused_node(node: &Value) -> Option<&Value> {
+    if node.get("focused").and_then(Value::as_bool) == Some(true) {
+        return Some(node);
+    }
+
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node.get(key)?.as_array())
+        .flatten()
+        .find_map(sway_focused_node)
+}
+
+fn watch_sway(path: &Path, on_focus: &mut dyn FnMut(Option<String>)) -> Result<(), AppError> {
+    let mut stream = UnixStream::connect(path)?;
+
+    write_sway_message(&mut stream, SWAY_GET_TREE, b"")?;
+    let (_, tree) = read_sway_message(&mut stream)?;
+    let tree: Value = serde_json::from_slice(&tree)?;
+    on_focus(sway_focused_node(&tree).and_then(sway_app_name));
+
+    write_sway_message(&mut stream, SWAY_SUBSCRIBE, br#"["window"]"#)?;
+    let (_, reply) = read_sway_message(&mut stream)?;
+    let reply: Value = serde_json::from_slice(&reply)?;
+    if reply.get("success").and_then(Value::as_bool) != Some(true) {
+        return Err(AppError::FocusError(format!(
+            "sway: Couldn't subscribe the window events: {reply}"
+        )));
+    }
+
+    loop {
+        let (_, event) = read_sway_message(&mut stream)?;
+        let event: Value = serde_json::from_slice(&event)?;
+        if event.get("change").and_then(Value::as_str) == Some("focus") {
+            on_focus(event.get("container").and_then(sway_app_name));
+        }
+    }
+}
+
+fn watch_hyprland(dir: &Path, on_focus: &mut dyn FnMut(Option<String>)) -> Result<(), AppError> {
+    // リクエスト用のソケットは応答後に閉じられる。
+    let mut request = UnixStream::connect(dir.join(".socket.sock"))?;
+    request.write_all(b"j/activewindow")?;
+    let mut reply = String::new();
+    request.read_to_string(&mut reply)?;
+    let window: Value = serde_json::from_str(&reply)?;
+    on_focus(
+        window
+            .get("class")
+            .and_then(Value::as_str)
+            .filter(|class| !class.is_empty())
+            .map(str::to_string),
+    );
+
+    // イベントは`activewindow>>CLASS,TITLE`のような行で届く。
+    let events = BufReader::new(UnixStream::connect(dir.join(".socket2.sock"))?);
+    for line in events.lines() {
+        let line = line?;
+        if let Some(window) = line.strip_prefix("activewindow>>") {
+            let class = window.split_once(',').map_or(window, |(class, _)| class);
+            on_focus((!class.is_empty()).then(|| class.to_string()));
+        }
+    }
+
+    Err(AppError::FocusError(
+        "Hyprland closed the connection.".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kanata_ime_observer_{}_{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn sway_focus_events() {
+        let path = temp_path("sway.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            assert_eq!(read_sway_message(&mut stream).unwrap().0, SWAY_GET_TREE);
+            let tree = br#"{"focused": false, "nodes": [{"focused": false, "nodes": [
+                {"focused": false, "app_id": "kitty", "nodes": []},
+                {"focused": true, "app_id": "foot", "nodes": []}
+            ]}], "floating_nodes": []}"#;
+            write_sway_message(&mut stream, SWAY_GET_TREE, tree).unwrap();
+
+            let (message_type, payload) = read_sway_message(&mut stream).unwrap();
+            assert_eq!(
+                (message_type, payload.as_slice()),
+                (SWAY_SUBSCRIBE, &br#"["window"]"#[..])
+            );
+            write_sway_message(&mut stream, SWAY_SUBSCRIBE, br#"{"success": true}"#).unwrap();
+
+            for event in [
+                &br#"{"change": "focus", "container": {"app_id": null, "window_properties": {"class": "steam_app_1"}}}"#[..],
+                br#"{"change": "title", "container": {"app_id": "foot"}}"#,
+                br#"{"change": "focus", "container": {"app_id": "kitty"}}"#,
+            ] {
+                write_sway_message(&mut stream, 0x80000003, event).unwrap();
+            }
+        });
+
+        let mut apps = Vec::new();
+        assert!(watch_sway(&path, &mut |app| apps.push(app)).is_err());
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(
+            apps,
+            vec![
+                Some("foot".to_string()),
+                Some("steam_app_1".to_string()),
+                Some("kitty".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn hyprland_focus_events() {
+        let dir = temp_path("hypr");
+        std::fs::create_dir_all(&dir).unwrap();
+        let request_listener = UnixListener::bind(dir.join(".socket.sock")).unwrap();
+        let event_listener = UnixListener::bind(dir.join(".socket2.sock")).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = request_listener.accept().unwrap();
+            let mut buf = [0; 64];
+            let n = stream.read(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"j/activewindow");
+            stream
+                .write_all(br#"{"class": "foot", "title": "~"}"#)
+                .unwrap();
+            drop(stream);
+
+            let (mut stream, _) = event_listener.accept().unwrap();
+            stream
+                .write_all(b"workspace>>2\nactivewindow>>kitty,~/src\nactivewindow>>,\n")
+                .unwrap();
+        });
+
+        let mut apps = Vec::new();
+        assert!(watch_hyprland(&dir, &mut |app| apps.push(app)).is_err());
+        server.join().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            apps,
+            vec![Some("foot".to_string()), Some("kitty".to_string()), None]
+        );
+    }
+
+    /// Xvfbなどで実行する。`xvfb-run cargo test -- --ignored`
+    #[test]
+    #[ignore = "needs an X server"]
+    fn x11_focus_events() {
+        use x11rb::{
+            connection::Connection,
+            protocol::xproto::{AtomEnum, ConnectionExt, CreateWindowAux, PropMode, WindowClass},
+            wrapper::ConnectionExt as _,
+        };
+
+        let (conn, screen_num) = x11rb::connect(None).unwrap();
+        let screen = &conn.setup().roots[screen_num];
+        let root = screen.root;
+        let net_active_window = conn
+            .intern_atom(false, b"_NET_ACTIVE_WINDOW")
+            .unwrap()
+            .reply()
+            .unwrap()
+            .atom;
+
+        let mut windows = Vec::new();
+        for wm_class in [&b"foot\0foot\0"[..], b"kitty\0kitty\0"] {
+            let window = conn.generate_id().unwrap();
+            conn.create_window(
+                screen.root_depth,
+                window,
+                root,
+                0,
+                0,
+                1,
+                1,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                screen.root_visual,
+                &CreateWindowAux::new(),
+            )
+            .unwrap();
+            conn.change_property8(
+                PropMode::REPLACE,
+                window,
+                AtomEnum::WM_CLASS,
+                AtomEnum::STRING,
+                wm_class,
+            )
+            .unwrap();
+            windows.push(window);
+        }
+        let activate = |window: u32| {
+            conn.change_property32(
+                PropMode::REPLACE,
+                root,
+                net_active_window,
+                AtomEnum::WINDOW,
+                &[window],
+            )
+            .unwrap();
+            conn.sync().unwrap();
+        };
+        activate(windows[0]);
+
+        let (app_sender, app_receiver) = std::sync::mpsc::channel();
+        watch_focus(FocusProvider::X11, move |app| {
+            let _ = app_sender.send(app);
+        });
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            app_receiver.recv_timeout(timeout).unwrap().as_deref(),
+            Some("foot")
+        );
+
+        activate(windows[1]);
+        assert_eq!(
+            app_receiver.recv_timeout(timeout).unwrap().as_deref(),
+            Some("kitty")
+        );
+
+        activate(0);
+        assert_eq!(app_receiver.recv_timeout(timeout).unwrap(), None);
+    }
+}
@@ -1,14 +1,31 @@
 use crate::{
-    AppError, FatalError, InnerReceiver, Message, MessageReceiver, handle_try_send,
-    send_fatal_error, send_message,
+    AppContext, AppError, FatalError, ImeMainLoop, ImeReceiver, InnerReceiver, Message,
+    MessageReceiver, handle_try_send, send_fatal_error, send_message,
 };
 
-use dbus::{blocking::SyncConnection, channel::Channel, message::MatchRule};
-use log::{debug, error, info};
+use dbus::{
+    arg::{ArgType, RefArg, Variant},
+    blocking::{Proxy, SyncConnection, stdintf::org_freedesktop_dbus::Properties},
+    channel::Channel,
+    message::MatchRule,
+};
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use std::{
+    collections::HashMap,
+    process::Command,
+    sync::{Mutex, mpsc::sync_channel},
+    time::Duration,
+};
 
-use std::{process::Command, sync::mpsc::sync_channel, time::Duration};
+/// エンジン名からエンジンの言語へのキャッシュ。
+static ENGINE_LANGUAGES: Lazy<Mutex<HashMap<String, Option<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
-pub fn dbus_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
+/// ibusのバスに接続する。
+fn connect_ibus() -> Result<SyncConnection, AppError> {
     let cmd_out = Command::new("ibus")
         .arg("address")
         .output()
@@ -21,6 +38,62 @@ pub fn dbus_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
     let conn: SyncConnection = Channel::open_private(&address)?.into();
     info!("Connected to address: '{}'", address);
 
+    Ok(conn)
+}
+
+/// IBusEngineDescの文字列のフィールドを取り出す。
+/// IBusEngineDescは(名前, attachments, エンジン名, 表示名, 説明, 言語, ...)の構造体。
+fn engine_desc_field(desc: &dyn RefArg, index: usize) -> Option<String> {
+    match desc.arg_type() {
+        ArgType::Variant => engine_desc_field(desc.as_iter()?.next()?, index),
+        ArgType::Struct => desc.as_iter()?.nth(index)?.as_str().map(str::to_string),
+        _ => None,
+    }
+}
+
+/// IBusEngineDescからエンジン名を取り出す。
+fn engine_name(desc: &dyn RefArg) -> Option<String> {
+    engine_desc_field(desc, 2)
+}
+
+/// IBusEngineDescから言語を取り出す。空の場合はNoneを返す。
+fn engine_language(desc: &dyn RefArg) -> Option<String> {
+    engine_desc_field(desc, 5).filter(|language| !language.is_empty())
+}
+
+/// エンジンの言語を取得する。
+fn get_engine_language(engine_name: &str) -> Result<Option<String>, AppError> {
+    let conn = connect_ibus()?;
+    let proxy = conn.with_proxy(
+        "org.freedesktop.IBus",
+        "/org/freedesktop/IBus",
+        Duration::from_millis(500),
+    );
+    let (descs,): (Vec<Variant<Box<dyn RefArg>>>,) = proxy.method_call(
+        "org.freedesktop.IBus",
+        "GetEnginesByNames",
+        (vec![engine_name],),
+    )?;
+
+    Ok(descs.first().and_then(|desc| engine_language(desc)))
+}
+
+/// 現在のエンジン名を取得する。GetGlobalEngineを持たないibusではGlobalEngineプロパティを読む。
+fn get_global_engine(proxy: &Proxy<'_, &SyncConnection>) -> Result<String, AppError> {
+    let desc: Variant<Box<dyn RefArg>> =
+        match proxy.method_call("org.freedesktop.IBus", "GetGlobalEngine", ()) {
+            Ok((desc,)) => desc,
+            Err(_) => proxy.get("org.freedesktop.IBus", "GlobalEngine")?,
+        };
+
+    engine_name(&desc).ok_or(AppError::DbusParseError(
+        "Couldn't read the global engine.".to_string(),
+    ))
+}
+
+pub fn dbus_main_loop(context: &AppContext, fatal_error: &FatalError) -> Result<(), AppError> {
+    let conn = connect_ibus()?;
+
     let proxy = conn.with_proxy(
         "org.freedesktop.IBus",
         "/org/freedesktop/IBus",
@@ -32,22 +105,33 @@ pub fn dbus_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
     let token = proxy.match_start(
         signal_rule,
         true,
-        Box::new(|message, _| {
-            match message.read1::<String>() {
-                Ok(engine_name) => {
-                    send_message(Message::ImeStatus(engine_name));
-                }
-                Err(_) => {
-                    error!(
-                        "{}",
-                        AppError::DbusParseError("Couldn't read GlobalEngineChanged.".to_string())
-                    );
+        Box::new({
+            let context = context.clone();
+            move |message, _| {
+                match message.read1::<String>() {
+                    Ok(engine_name) => {
+                        send_message(&context, Message::ImeStatus(engine_name));
+                    }
+                    Err(_) => {
+                        error!(
+                            "{}",
+                            AppError::DbusParseError(
+                                "Couldn't read GlobalEngineChanged.".to_string()
+                            )
+                        );
+                    }
                 }
+                true
             }
-            true
         }),
     )?;
 
+    // 起動時の状態を取得する。
+    match get_global_engine(&proxy) {
+        Ok(engine_name) => send_message(context, Message::ImeStatus(engine_name)),
+        Err(e) => warn!("Couldn't get the current engine: {e}"),
+    }
+
     // メインループ
     while fatal_error.is_none() {
         conn.process(Duration::from_millis(1000))?;
@@ -61,7 +145,8 @@ pub fn dbus_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
     })
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct IbusImeReceiverConfig {}
 
 #[allow(clippy::derivable_impls)]
@@ -72,13 +157,17 @@ impl Default for IbusImeReceiverConfig {
 }
 
 pub struct IbusImeReceiver {
+    context: AppContext,
     _worker_handle: std::thread::JoinHandle<MessageReceiver>,
     inner_receiver: InnerReceiver,
     pre_ime_status: Option<String>,
 }
 
-impl IbusImeReceiver {
-    pub fn new(
+impl ImeReceiver for IbusImeReceiver {
+    type Config = IbusImeReceiverConfig;
+
+    fn new(
+        context: &AppContext,
         message_receiver: MessageReceiver,
         config: &IbusImeReceiverConfig,
         fatal_error: &FatalError,
@@ -88,6 +177,7 @@ impl IbusImeReceiver {
         let (inner_sender, inner_receiver) = sync_channel(1);
 
         let _worker_handle = std::thread::spawn({
+            let context = context.clone();
             let fatal_error = fatal_error.clone();
 
             move || {
@@ -97,6 +187,7 @@ impl IbusImeReceiver {
                     match msg {
                         Message::ImeStatus(ime_status) => {
                             handle_try_send(
+                                &context,
                                 &inner_sender,
                                 ime_status,
                                 "IbusImeReceiver inner sender".to_string(),
@@ -104,6 +195,7 @@ impl IbusImeReceiver {
                         }
                         Message::CaughtFatalError => {
                             handle_try_send(
+                                &context,
                                 &inner_sender,
                                 String::new(),
                                 "IbusImeReceiver inner sender".to_string(),
@@ -117,12 +209,13 @@ impl IbusImeReceiver {
         });
 
         Ok(Self {
+            context: context.clone(),
             _worker_handle,
             inner_receiver,
             pre_ime_status: None,
         })
     }
-    pub fn receive(&mut self) -> Result<String, AppError> {
+    fn receive(&mut self) -> Result<String, AppError> {
         loop {
             let new_ime_status =
                 self.inner_receiver
@@ -142,11 +235,99 @@ impl IbusImeReceiver {
             }
         }
     }
-    pub fn shutdown(self) -> MessageReceiver {
-        send_fatal_error(AppError::CustomError("Receiver shutdown.".to_string()));
+    fn shutdown(self) -> MessageReceiver {
+        send_fatal_error(
+            &self.context,
+            AppError::CustomError("Receiver shutdown.".to_string()),
+        );
         let message_receiver = self._worker_handle.join().expect("worker thread panicked.");
         debug!("IbusImeReceiver shutdown.");
 
         message_receiver
     }
+    const CAN_SWITCH_IME: bool = true;
+
+    fn switch_ime(ime_status: &str) -> Result<(), AppError> {
+        let conn = connect_ibus()?;
+        let proxy = conn.with_proxy(
+            "org.freedesktop.IBus",
+            "/org/freedesktop/IBus",
+            Duration::from_millis(500),
+        );
+        proxy.method_call::<(), _, _, _>(
+            "org.freedesktop.IBus",
+            "SetGlobalEngine",
+            (ime_status,),
+        )?;
+        Ok(())
+    }
+
+    fn ime_language(ime_status: &str) -> Option<String> {
+        let mut engine_languages = ENGINE_LANGUAGES.lock().expect("engine languages poisoned.");
+        if let Some(language) = engine_languages.get(ime_status) {
+            return language.clone();
+        }
+
+        match get_engine_language(ime_status) {
+            Ok(language) => {
+                debug!("Language of the engine '{ime_status}': {language:?}");
+                engine_languages.insert(ime_status.to_string(), language.clone());
+                language
+            }
+            Err(e) => {
+                warn!("Couldn't get the language of the engine '{ime_status}': {e}");
+                None
+            }
+        }
+    }
+}
+
+impl ImeMainLoop for IbusImeReceiver {
+    fn main_loop(
+        context: &AppContext,
+        _config: &Self::Config,
+        fatal_error: &FatalError,
+    ) -> Result<(), AppError> {
+        dbus_main_loop(context, fatal_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::{HashMap, VecDeque};
+
+    #[test]
+    fn read_engine_name_from_engine_desc() {
+        let desc: VecDeque<Box<dyn RefArg>> = VecDeque::from([
+            Box::new("IBusEngineDesc".to_string()) as Box<dyn RefArg>,
+            Box::new(HashMap::<String, Variant<Box<dyn RefArg>>>::new()),
+            Box::new("mozc-jp".to_string()),
+            Box::new("Mozc".to_string()),
+        ]);
+        let desc = Variant(Box::new(Variant(Box::new(desc) as Box<dyn RefArg>)) as Box<dyn RefArg>);
+
+        assert_eq!(engine_name(&desc), Some("mozc-jp".to_string()));
+        assert_eq!(engine_language(&desc), None);
+        assert_eq!(
+            engine_name(&Variant(Box::new(1_u32) as Box<dyn RefArg>)),
+            None
+        );
+    }
+
+    #[test]
+    fn read_language_from_engine_desc() {
+        let desc: VecDeque<Box<dyn RefArg>> = VecDeque::from([
+            Box::new("IBusEngineDesc".to_string()) as Box<dyn RefArg>,
+            Box::new(HashMap::<String, Variant<Box<dyn RefArg>>>::new()),
+            Box::new("anthy".to_string()),
+            Box::new("Anthy".to_string()),
+            Box::new("Anthy Input Method".to_string()),
+            Box::new("ja".to_string()),
+        ]);
+        let desc = Variant(Box::new(desc) as Box<dyn RefArg>);
+
+        assert_eq!(engine_language(&desc), Some("ja".to_string()));
+    }
 }
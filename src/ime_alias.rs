@@ -0,0 +1,147 @@
+use crate::AppError;
+use crate::ime_map::{ImeContext, ImeMap};
+
+/// 組み込みの別名。別名とバックエンドごとのIME名(ibus, fcitx5, mac, win, win_onoff)。
+const BUILTIN_ALIASES: &[(&str, &[&str])] = &[
+    (
+        "japanese",
+        &[
+            "mozc-jp",
+            "mozc",
+            "anthy",
+            "kkc",
+            "skk",
+            "com.google.inputmethod.Japanese.base",
+            "com.apple.inputmethod.Kotoeri.*.Japanese",
+            "ja-JP",
+        ],
+    ),
+    (
+        "korean",
+        &["hangul", "com.apple.inputmethod.Korean.2SetKorean", "ko-KR"],
+    ),
+    (
+        "pinyin",
+        &[
+            "libpinyin",
+            "pinyin",
+            "com.apple.inputmethod.SCIM.ITABC",
+            "zh-CN",
+        ],
+    ),
+    (
+        "us",
+        &[
+            "xkb:us::eng",
+            "keyboard-us",
+            "com.apple.keylayout.US",
+            "com.apple.keylayout.ABC",
+            "en-US",
+        ],
+    ),
+];
+
+/// バックエンドごとのIME名から別名へのテーブル。ユーザーの別名は組み込みの別名より優先する。
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImeAliases {
+    user: ImeMap<String>,
+    builtin: Option<ImeMap<String>>,
+}
+
+impl ImeAliases {
+    /// 別名とIME名(パターン)の一覧から作成する。use_builtinがtrueの場合は組み込みの別名も使う。
+    pub fn new(
+        aliases: impl IntoIterator<Item = (String, Vec<String>)>,
+        use_builtin: bool,
+    ) -> Result<Self, AppError> {
+        let user = ImeMap::new(aliases.into_iter().flat_map(|(alias, ime_names)| {
+            ime_names
+                .into_iter()
+                .map(move |ime_name| (ime_name, alias.clone()))
+        }))?;
+
+        let builtin = if use_builtin {
+            Some(ImeMap::new(BUILTIN_ALIASES.iter().flat_map(
+                |(alias, ime_names)| {
+                    ime_names
+                        .iter()
+                        .map(|ime_name| (ime_name.to_string(), alias.to_string()))
+                },
+            ))?)
+        } else {
+            None
+        };
+
+        Ok(Self { user, builtin })
+    }
+
+    /// IME名の別名。別名がない場合、別名がIME名と同じ場合はNoneを返す。
+    pub fn alias(&self, ime_status: &str) -> Option<String> {
+        self.user
+            .get(ime_status, ImeContext::default())
+            .or_else(|| {
+                self.builtin
+                    .as_ref()?
+                    .get(ime_status, ImeContext::default())
+            })
+            .filter(|alias| alias != ime_status)
+    }
+}
+
+impl Default for ImeAliases {
+    fn default() -> Self {
+        Self::new([], false).expect("empty aliases are invalid.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_aliases() {
+        assert_eq!(ImeAliases::default().alias("mozc-jp"), None);
+
+        let aliases = ImeAliases::new([], true).unwrap();
+        assert_eq!(aliases.alias("mozc-jp").as_deref(), Some("japanese"));
+        assert_eq!(
+            aliases
+                .alias("com.apple.inputmethod.Kotoeri.RomajiTyping.Japanese")
+                .as_deref(),
+            Some("japanese")
+        );
+        assert_eq!(aliases.alias("keyboard-us").as_deref(), Some("us"));
+        assert_eq!(aliases.alias("xkb:de::ger"), None);
+        // win_onoffのIMEのオン・オフは言語を表さない。
+        assert_eq!(aliases.alias("ime-on"), None);
+    }
+
+    #[test]
+    fn user_aliases_have_priority() {
+        let aliases = ImeAliases::new(
+            [
+                ("jp".to_string(), vec!["mozc-jp".to_string()]),
+                ("latin".to_string(), vec!["xkb:*".to_string()]),
+            ],
+            true,
+        )
+        .unwrap();
+        assert_eq!(aliases.alias("mozc-jp").as_deref(), Some("jp"));
+        assert_eq!(aliases.alias("mozc").as_deref(), Some("japanese"));
+        assert_eq!(aliases.alias("xkb:us::eng").as_deref(), Some("latin"));
+
+        let aliases =
+            ImeAliases::new([("jp".to_string(), vec!["mozc-jp".to_string()])], false).unwrap();
+        assert_eq!(aliases.alias("mozc"), None);
+        assert!(
+            ImeAliases::new(
+                [
+                    ("jp".to_string(), vec!["mozc".to_string()]),
+                    ("ja".to_string(), vec!["mozc".to_string()]),
+                ],
+                false
+            )
+            .is_err()
+        );
+    }
+}
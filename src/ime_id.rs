@@ -0,0 +1,233 @@
+use crate::AppError;
+
+use std::fmt;
+
+/// IME名のフィールド。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImeField {
+    Framework,
+    Layout,
+    Variant,
+    Language,
+    Region,
+    Mode,
+}
+
+impl ImeField {
+    pub const ALL: [ImeField; 6] = [
+        ImeField::Framework,
+        ImeField::Layout,
+        ImeField::Variant,
+        ImeField::Language,
+        ImeField::Region,
+        ImeField::Mode,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ImeField::Framework => "framework",
+            ImeField::Layout => "layout",
+            ImeField::Variant => "variant",
+            ImeField::Language => "language",
+            ImeField::Region => "region",
+            ImeField::Mode => "mode",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, AppError> {
+        Self::ALL
+            .into_iter()
+            .find(|field| field.name() == name)
+            .ok_or_else(|| {
+                AppError::ArgError(format!(
+                    "Unknown IME field '{name}'. Use framework, layout, variant, language, region or mode."
+                ))
+            })
+    }
+}
+
+/// バックエンドが返すIME名をフィールドに分けたもの。構造を持たないIME名は全てNoneとなる。
+/// - ibus: `xkb:us:intl:eng`(レイアウト・バリアント・言語)、`m17n:hi:inscript`(言語・モード)
+/// - fcitx5: `keyboard-us-intl`(レイアウト・バリアント)
+/// - mac: `com.apple.keylayout.US`(レイアウト)、`com.apple.inputmethod.Kotoeri.RomajiTyping.Japanese`(モード・言語)
+/// - win: `ja-JP`(言語・地域)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImeId<'a> {
+    pub framework: Option<&'a str>,
+    pub layout: Option<&'a str>,
+    pub variant: Option<&'a str>,
+    pub language: Option<&'a str>,
+    pub region: Option<&'a str>,
+    pub mode: Option<&'a str>,
+}
+
+impl<'a> ImeId<'a> {
+    pub fn parse(ime_status: &'a str) -> Self {
+        let non_empty = |s: &'a str| (!s.is_empty()).then_some(s);
+
+        if let Some(rest) = ime_status.strip_prefix("xkb:") {
+            let mut parts = rest.splitn(3, ':');
+            return Self {
+                framework: Some("xkb"),
+                layout: parts.next().and_then(non_empty),
+                variant: parts.next().and_then(non_empty),
+                language: parts.next().and_then(non_empty),
+                region: None,
+                mode: None,
+            };
+        }
+
+        if let Some(rest) = ime_status.strip_prefix("m17n:") {
+            let mut parts = rest.splitn(2, ':');
+            return Self {
+                framework: Some("m17n"),
+                language: parts.next().and_then(non_empty),
+                mode: parts.next().and_then(non_empty),
+                ..Default::default()
+            };
+        }
+
+        if let Some(rest) = ime_status.strip_prefix("keyboard-") {
+            let mut parts = rest.splitn(2, '-');
+            return Self {
+                framework: Some("keyboard"),
+                layout: parts.next().and_then(non_empty),
+                variant: parts.next().and_then(non_empty),
+                ..Default::default()
+            };
+        }
+
+        // winのロケール名。`mozc-jp`などと区別するため、地域は大文字2文字か数字3桁に限る。
+        if let Some((language, region)) = ime_status.split_once('-')
+            && (2..=3).contains(&language.len())
+            && language.bytes().all(|b| b.is_ascii_lowercase())
+            && ((region.len() == 2 && region.bytes().all(|b| b.is_ascii_uppercase()))
+                || (region.len() == 3 && region.bytes().all(|b| b.is_ascii_digit())))
+        {
+            return Self {
+                language: Some(language),
+                region: Some(region),
+                ..Default::default()
+            };
+        }
+
+        // 逆ドメイン名のmacの入力ソースID。n番目の`.`で分ける。
+        let split_dot = |n: usize| {
+            ime_status
+                .match_indices('.')
+                .nth(n - 1)
+                .map(|(i, _)| (&ime_status[..i], &ime_status[i + 1..]))
+        };
+        let ime_id = match ime_status.split('.').nth(2) {
+            Some("keylayout") => split_dot(3).map(|(framework, layout)| Self {
+                framework: Some(framework),
+                layout: non_empty(layout),
+                ..Default::default()
+            }),
+            Some("inputmethod") => split_dot(4).map(|(framework, rest)| {
+                let (mode, language) = match rest.split_once('.') {
+                    Some((mode, language)) => (mode, Some(language)),
+                    None => (rest, None),
+                };
+                Self {
+                    framework: Some(framework),
+                    mode: non_empty(mode),
+                    language: language.and_then(non_empty),
+                    ..Default::default()
+                }
+            }),
+            _ => None,
+        };
+        ime_id.unwrap_or_default()
+    }
+
+    pub fn field(&self, field: ImeField) -> Option<&'a str> {
+        match field {
+            ImeField::Framework => self.framework,
+            ImeField::Layout => self.layout,
+            ImeField::Variant => self.variant,
+            ImeField::Language => self.language,
+            ImeField::Region => self.region,
+            ImeField::Mode => self.mode,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        ImeField::ALL
+            .into_iter()
+            .all(|field| self.field(field).is_none())
+    }
+}
+
+/// ルールと同じ`layout=us,variant=intl`の形式で表示する。
+impl fmt::Display for ImeId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = ImeField::ALL
+            .into_iter()
+            .filter_map(|field| Some(format!("{}={}", field.name(), self.field(field)?)))
+            .collect::<Vec<_>>();
+        write!(f, "{}", fields.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ibus_and_fcitx() {
+        assert_eq!(
+            ImeId::parse("xkb:us:intl:eng"),
+            ImeId {
+                framework: Some("xkb"),
+                layout: Some("us"),
+                variant: Some("intl"),
+                language: Some("eng"),
+                region: None,
+                mode: None,
+            }
+        );
+        assert_eq!(
+            ImeId::parse("xkb:jp::jpn").to_string(),
+            "framework=xkb,layout=jp,language=jpn"
+        );
+        assert_eq!(
+            ImeId::parse("m17n:hi:inscript").to_string(),
+            "framework=m17n,language=hi,mode=inscript"
+        );
+        assert_eq!(
+            ImeId::parse("keyboard-us-alt-intl").to_string(),
+            "framework=keyboard,layout=us,variant=alt-intl"
+        );
+        assert_eq!(
+            ImeId::parse("keyboard-jp").to_string(),
+            "framework=keyboard,layout=jp"
+        );
+        assert!(ImeId::parse("mozc-jp").is_empty());
+    }
+
+    #[test]
+    fn parse_mac() {
+        assert_eq!(
+            ImeId::parse("com.apple.keylayout.US").to_string(),
+            "framework=com.apple.keylayout,layout=US"
+        );
+        assert_eq!(
+            ImeId::parse("com.apple.inputmethod.Kotoeri.RomajiTyping.Japanese").to_string(),
+            "framework=com.apple.inputmethod.Kotoeri,language=Japanese,mode=RomajiTyping"
+        );
+        assert_eq!(
+            ImeId::parse("com.google.inputmethod.Japanese.Roman").to_string(),
+            "framework=com.google.inputmethod.Japanese,mode=Roman"
+        );
+        assert!(ImeId::parse("com.example.unknown").is_empty());
+    }
+
+    #[test]
+    fn parse_win() {
+        assert_eq!(ImeId::parse("ja-JP").to_string(), "language=ja,region=JP");
+        assert_eq!(ImeId::parse("es-419").to_string(), "language=es,region=419");
+        assert!(ImeId::parse("mozc-jp").is_empty());
+        assert!(ImeId::parse("ime-on").is_empty());
+    }
+}
@@ -0,0 +1,678 @@
+use crate::AppError;
+use crate::ime_id::{ImeField, ImeId};
+
+use std::collections::HashMap;
+
+use regex::{Captures, Regex};
+
+/// IME名・アプリケーション名のパターン。`re:`で始まる場合は正規表現、`*`・`?`を含む場合はglobとする。
+/// IME名の`lang:`はIMEの言語、`layout=us,mode=Romaji*`はIME名のフィールド(値はglob)を表す。
+#[derive(Debug, Clone)]
+enum NamePattern {
+    Glob {
+        source: String,
+        regex: Regex,
+    },
+    Regex {
+        source: String,
+        regex: Regex,
+    },
+    Language {
+        source: String,
+        language: String,
+    },
+    Fields {
+        source: String,
+        fields: Vec<(ImeField, Regex)>,
+    },
+}
+
+/// `*`・`?`のglobを正規表現にする。
+fn glob_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let pattern = glob
+        .chars()
+        .map(|c| match c {
+            '*' => ".*".to_string(),
+            '?' => ".".to_string(),
+            c => regex::escape(c.encode_utf8(&mut [0; 4])),
+        })
+        .collect::<String>();
+    Regex::new(&format!("^{pattern}$"))
+}
+
+impl NamePattern {
+    /// パターンでなければNoneを返す。
+    fn parse(name: &str) -> Result<Option<Self>, AppError> {
+        let invalid =
+            |e: regex::Error| AppError::ArgError(format!("Invalid name pattern '{name}': {e}"));
+
+        if let Some(language) = name.strip_prefix("lang:") {
+            return Ok(Some(NamePattern::Language {
+                source: name.to_string(),
+                language: language.to_string(),
+            }));
+        }
+
+        if let Some(pattern) = name.strip_prefix("re:") {
+            let regex = Regex::new(&format!("^(?:{pattern})$")).map_err(invalid)?;
+            return Ok(Some(NamePattern::Regex {
+                source: name.to_string(),
+                regex,
+            }));
+        }
+
+        if name.split_once('=').is_some_and(|(field, _)| {
+            !field.is_empty() && field.chars().all(|c| c.is_ascii_lowercase())
+        }) {
+            let fields = name
+                .split(',')
+                .map(|condition| {
+                    let (field, value) = condition.split_once('=').ok_or_else(|| {
+                        AppError::ArgError(format!(
+                            "Invalid name pattern '{name}': '{condition}' has no '='."
+                        ))
+                    })?;
+                    Ok((
+                        ImeField::from_name(field)?,
+                        glob_regex(value).map_err(invalid)?,
+                    ))
+                })
+                .collect::<Result<_, AppError>>()?;
+            return Ok(Some(NamePattern::Fields {
+                source: name.to_string(),
+                fields,
+            }));
+        }
+
+        if name.contains(['*', '?']) {
+            let regex = glob_regex(name).map_err(invalid)?;
+            return Ok(Some(NamePattern::Glob {
+                source: name.to_string(),
+                regex,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    fn source(&self) -> &str {
+        match self {
+            NamePattern::Glob { source, .. }
+            | NamePattern::Regex { source, .. }
+            | NamePattern::Language { source, .. }
+            | NamePattern::Fields { source, .. } => source,
+        }
+    }
+
+    /// 一致した場合はキャプチャを返す。正規表現以外はキャプチャを持たない。
+    fn captures<'h>(&self, name: &'h str, language: Option<&str>) -> Option<Option<Captures<'h>>> {
+        match self {
+            NamePattern::Glob { regex, .. } => regex.is_match(name).then_some(None),
+            NamePattern::Regex { regex, .. } => regex.captures(name).map(Some),
+            NamePattern::Language {
+                language: pattern, ..
+            } => language
+                .is_some_and(|language| language_matches(pattern, language))
+                .then_some(None),
+            // 値のないフィールドは空文字列として比べる。
+            NamePattern::Fields { fields, .. } => {
+                let ime_id = ImeId::parse(name);
+                fields
+                    .iter()
+                    .all(|(field, regex)| regex.is_match(ime_id.field(*field).unwrap_or_default()))
+                    .then_some(None)
+            }
+        }
+    }
+}
+
+/// `zh`は`zh`・`zh_CN`・`zh-TW`に一致し、`zh_CN`は`zh_CN`のみに一致する。
+fn language_matches(pattern: &str, language: &str) -> bool {
+    language
+        .strip_prefix(pattern)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['_', '-']))
+}
+
+/// IME名以外の照合の条件。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImeContext<'a> {
+    /// IMEの別名。完全一致・パターンのそれぞれでIME名より先に試す。
+    pub alias: Option<&'a str>,
+    /// IMEの言語(`ja`・`zh_CN`など)。バックエンドが言語を持たない場合はNone。
+    pub language: Option<&'a str>,
+    /// フォーカス中のアプリケーション。
+    pub app_name: Option<&'a str>,
+}
+
+/// フォーカス中のアプリケーション(app idまたはclass)の条件。
+#[derive(Debug, Clone)]
+struct AppCondition {
+    source: String,
+    pattern: Option<NamePattern>,
+}
+
+impl AppCondition {
+    fn parse(app_name: String) -> Result<Self, AppError> {
+        let pattern = NamePattern::parse(&app_name)?;
+        if let Some(NamePattern::Language { .. } | NamePattern::Fields { .. }) = pattern {
+            return Err(AppError::ArgError(format!(
+                "Invalid application name '{app_name}'."
+            )));
+        }
+
+        Ok(Self {
+            pattern,
+            source: app_name,
+        })
+    }
+
+    fn matches(&self, app_name: &str) -> bool {
+        match &self.pattern {
+            Some(pattern) => pattern.captures(app_name, None).is_some(),
+            None => self.source == app_name,
+        }
+    }
+}
+
+/// ImeMapの値。正規表現のキャプチャで置き換えられる。
+pub trait ImeMapValue: Clone {
+    fn expand(&self, _captures: &Captures) -> Self {
+        self.clone()
+    }
+}
+
+/// `$1`・`${name}`をキャプチャで置き換える。
+impl ImeMapValue for String {
+    fn expand(&self, captures: &Captures) -> Self {
+        let mut expanded = String::new();
+        captures.expand(self, &mut expanded);
+        expanded
+    }
+}
+
+impl ImeMapValue for usize {}
+
+/// IME名から値へのマップ。完全一致を優先し、その後パターンを定義順に試す。
+/// 値がNoneのIMEは無視するIMEで、デフォルト値も使わない。
+#[derive(Debug, Clone)]
+pub struct ImeMap<T> {
+    exact: HashMap<String, Option<T>>,
+    patterns: Vec<(NamePattern, Option<T>)>,
+    /// どのIMEにも一致しない場合の値。
+    default: Option<T>,
+    /// フォーカス中のアプリケーションごとのマップ。全体のマップより優先し、定義順に試す。
+    apps: Vec<(AppCondition, ImeMap<T>)>,
+}
+
+impl<T: ImeMapValue> ImeMap<T> {
+    /// IME名(パターン)と値の組から作成する。同じIME名が複数ある場合はエラーとする。
+    pub fn new(entries: impl IntoIterator<Item = (String, T)>) -> Result<Self, AppError> {
+        let mut ime_map = Self {
+            exact: HashMap::new(),
+            patterns: Vec::new(),
+            default: None,
+            apps: Vec::new(),
+        };
+        for (ime_name, value) in entries {
+            ime_map.insert(ime_name, Some(value))?;
+        }
+
+        Ok(ime_map)
+    }
+
+    /// 無視するIMEを追加する。無視するIMEのパターンは値のあるパターンより優先する。
+    pub fn with_ignored(
+        mut self,
+        ime_names: impl IntoIterator<Item = String>,
+    ) -> Result<Self, AppError> {
+        let patterns = std::mem::take(&mut self.patterns);
+        for ime_name in ime_names {
+            self.insert(ime_name, None)?;
+        }
+        for (pattern, value) in patterns {
+            self.insert_pattern(pattern, value)?;
+        }
+
+        Ok(self)
+    }
+
+    /// どのIMEにも一致しない場合の値を設定する。
+    pub fn with_default(mut self, default: Option<T>) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// アプリケーション名(パターン)がフォーカス中の場合のマップを追加する。デフォルト値は使わない。
+    pub fn with_app(mut self, app_name: String, ime_map: ImeMap<T>) -> Result<Self, AppError> {
+        if self
+            .apps
+            .iter()
+            .any(|(condition, _)| condition.source == app_name)
+        {
+            return Err(AppError::ArgError(format!(
+                "Duplicate application name '{app_name}'."
+            )));
+        }
+        self.apps.push((AppCondition::parse(app_name)?, ime_map));
+
+        Ok(self)
+    }
+
+    fn insert(&mut self, ime_name: String, value: Option<T>) -> Result<(), AppError> {
+        match NamePattern::parse(&ime_name)? {
+            Some(pattern) => self.insert_pattern(pattern, value),
+            None if self.exact.contains_key(&ime_name) => Err(AppError::ArgError(format!(
+                "Duplicate IME name '{ime_name}'."
+            ))),
+            None => {
+                self.exact.insert(ime_name, value);
+                Ok(())
+            }
+        }
+    }
+
+    fn insert_pattern(&mut self, pattern: NamePattern, value: Option<T>) -> Result<(), AppError> {
+        if self
+            .patterns
+            .iter()
+            .any(|(other, _)| other.source() == pattern.source())
+        {
+            return Err(AppError::ArgError(format!(
+                "Duplicate IME name '{}'.",
+                pattern.source()
+            )));
+        }
+        self.patterns.push((pattern, value));
+        Ok(())
+    }
+
+    /// IME名と条件に対応する値。正規表現のキャプチャは値の中で置き換える。
+    /// 一致しない場合はデフォルト値、無視するIMEの場合はNoneを返す。
+    pub fn get(&self, ime_name: &str, context: ImeContext) -> Option<T> {
+        self.matched(ime_name, context)
+            .unwrap_or_else(|| self.default.clone())
+    }
+
+    pub fn contains(&self, ime_name: &str, context: ImeContext) -> bool {
+        self.get(ime_name, context).is_some()
+    }
+
+    /// 無視するIMEかどうか。
+    pub fn is_ignored(&self, ime_name: &str, context: ImeContext) -> bool {
+        matches!(self.matched(ime_name, context), Some(None))
+    }
+
+    /// 一致したIME名(パターン)の値。どれにも一致しない場合はNoneを返す。
+    fn matched(&self, ime_name: &str, context: ImeContext) -> Option<Option<T>> {
+        let app_matched = context.app_name.and_then(|app_name| {
+            self.apps
+                .iter()
+                .filter(|(condition, _)| condition.matches(app_name))
+                .find_map(|(_, ime_map)| {
+                    ime_map.matched(
+                        ime_name,
+                        ImeContext {
+                            app_name: None,
+                            ..context
+                        },
+                    )
+                })
+        });
+        if app_matched.is_some() {
+            return app_matched;
+        }
+
+        // 完全一致を別名・IME名の順に試してから、パターンを別名・IME名の順に試す。
+        let names = || context.alias.into_iter().chain([ime_name]);
+        names()
+            .find_map(|name| self.exact.get(name).cloned())
+            .or_else(|| names().find_map(|name| self.pattern_matched(name, context.language)))
+    }
+
+    /// アプリケーションごとのマップを除いて一致したパターンの値。
+    fn pattern_matched(&self, ime_name: &str, language: Option<&str>) -> Option<Option<T>> {
+        self.patterns.iter().find_map(|(pattern, value)| {
+            pattern
+                .captures(ime_name, language)
+                .map(|captures| match captures {
+                    Some(captures) => value.as_ref().map(|value| value.expand(&captures)),
+                    None => value.clone(),
+                })
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty()
+            && self.patterns.is_empty()
+            && self.default.is_none()
+            && self.apps.is_empty()
+    }
+
+    /// フォーカス中のアプリケーションによって値が変わるかどうか。
+    pub fn has_app_rules(&self) -> bool {
+        !self.apps.is_empty()
+    }
+
+    /// IMEの言語によって値が変わるかどうか。
+    pub fn has_language_rules(&self) -> bool {
+        self.patterns
+            .iter()
+            .any(|(pattern, _)| matches!(pattern, NamePattern::Language { .. }))
+            || self
+                .apps
+                .iter()
+                .any(|(_, ime_map)| ime_map.has_language_rules())
+    }
+
+    /// アプリケーションによらない完全一致のIME名と値。値からIMEを逆引きする際に利用する。無視するIMEは含まない。
+    pub fn exact_entries(&self) -> impl Iterator<Item = (&String, &T)> {
+        self.exact
+            .iter()
+            .filter_map(|(ime_name, value)| value.as_ref().map(|value| (ime_name, value)))
+    }
+}
+
+impl ImeMap<String> {
+    /// キャプチャで置き換えない値。kanataのレイヤー名と照合する際に利用する。
+    pub fn fixed_values(&self) -> impl Iterator<Item = &String> {
+        self.own_fixed_values().chain(
+            self.apps
+                .iter()
+                .flat_map(|(_, ime_map)| ime_map.own_fixed_values()),
+        )
+    }
+
+    fn own_fixed_values(&self) -> impl Iterator<Item = &String> {
+        self.exact
+            .values()
+            .flatten()
+            .chain(
+                self.patterns
+                    .iter()
+                    .filter_map(|(pattern, value)| value.as_ref().map(|value| (pattern, value)))
+                    .filter(|(pattern, value)| {
+                        !matches!(pattern, NamePattern::Regex { .. }) || !value.contains('$')
+                    })
+                    .map(|(_, value)| value),
+            )
+            .chain(self.default.iter())
+    }
+}
+
+impl<T: PartialEq> PartialEq for ImeMap<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.exact == other.exact
+            && self.default == other.default
+            && self.patterns.len() == other.patterns.len()
+            && self.patterns.iter().zip(other.patterns.iter()).all(
+                |((pattern, value), (other_pattern, other_value))| {
+                    pattern.source() == other_pattern.source() && value == other_value
+                },
+            )
+            && self.apps.len() == other.apps.len()
+            && self.apps.iter().zip(other.apps.iter()).all(
+                |((condition, ime_map), (other_condition, other_ime_map))| {
+                    condition.source == other_condition.source && ime_map == other_ime_map
+                },
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer_map(entries: &[(&str, &str)]) -> ImeMap<String> {
+        ImeMap::new(
+            entries
+                .iter()
+                .map(|(ime, layer)| (ime.to_string(), layer.to_string())),
+        )
+        .unwrap()
+    }
+
+    fn app(app_name: &str) -> ImeContext<'_> {
+        ImeContext {
+            app_name: Some(app_name),
+            ..Default::default()
+        }
+    }
+
+    fn language(language: &str) -> ImeContext<'_> {
+        ImeContext {
+            language: Some(language),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exact_match_has_priority() {
+        let map = layer_map(&[("xkb:*", "base"), ("xkb:us::eng", "us")]);
+        assert_eq!(
+            map.get("xkb:us::eng", ImeContext::default()).as_deref(),
+            Some("us")
+        );
+        assert_eq!(
+            map.get("xkb:de::ger", ImeContext::default()).as_deref(),
+            Some("base")
+        );
+        assert_eq!(map.get("mozc-jp", ImeContext::default()), None);
+    }
+
+    #[test]
+    fn patterns_in_order() {
+        let map = layer_map(&[("xkb:us:*", "us"), ("xkb:*", "base"), ("xkb:?e:*", "never")]);
+        assert_eq!(
+            map.get("xkb:us:intl:eng", ImeContext::default()).as_deref(),
+            Some("us")
+        );
+        assert_eq!(
+            map.get("xkb:de::ger", ImeContext::default()).as_deref(),
+            Some("base")
+        );
+    }
+
+    #[test]
+    fn regex_capture_template() {
+        let map = layer_map(&[(r"re:xkb:(\w+):.*", "base-$1"), ("re:mozc.*", "ja")]);
+        assert_eq!(
+            map.get("xkb:de::ger", ImeContext::default()).as_deref(),
+            Some("base-de")
+        );
+        assert_eq!(
+            map.get("mozc-jp", ImeContext::default()).as_deref(),
+            Some("ja")
+        );
+        // 全体に一致する必要がある。
+        assert_eq!(map.get("ibus-mozc", ImeContext::default()), None);
+        assert_eq!(
+            map.fixed_values().collect::<Vec<_>>(),
+            vec![&"ja".to_string()]
+        );
+    }
+
+    #[test]
+    fn config_index_with_pattern() {
+        let map = ImeMap::new([("xkb:*".to_string(), 0), ("mozc".to_string(), 1)]).unwrap();
+        assert_eq!(map.get("xkb:us::eng", ImeContext::default()), Some(0));
+        assert_eq!(map.get("mozc", ImeContext::default()), Some(1));
+    }
+
+    #[test]
+    fn default_and_ignored() {
+        let map = layer_map(&[("mozc", "ja"), ("xkb:*", "base")])
+            .with_ignored(["xkb:de::ger".to_string(), "anthy*".to_string()])
+            .unwrap()
+            .with_default(Some("us".to_string()));
+        assert_eq!(
+            map.get("mozc", ImeContext::default()).as_deref(),
+            Some("ja")
+        );
+        assert_eq!(
+            map.get("xkb:us::eng", ImeContext::default()).as_deref(),
+            Some("base")
+        );
+        assert_eq!(map.get("xkb:de::ger", ImeContext::default()), None);
+        assert_eq!(map.get("anthy-jp", ImeContext::default()), None);
+        assert_eq!(
+            map.get("hangul", ImeContext::default()).as_deref(),
+            Some("us")
+        );
+        assert!(!map.contains("anthy-jp", ImeContext::default()));
+        assert!(map.is_ignored("anthy-jp", ImeContext::default()));
+        assert!(!map.is_ignored("hangul", ImeContext::default()));
+        assert!(
+            layer_map(&[("mozc", "ja")])
+                .with_ignored(["mozc".to_string()])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn app_rules_have_priority() {
+        let map = layer_map(&[("mozc", "oyayubi")])
+            .with_app("kitty".to_string(), layer_map(&[("mozc", "oyayubi-term")]))
+            .unwrap()
+            .with_app(
+                "steam_app_*".to_string(),
+                layer_map(&[]).with_ignored(["*".to_string()]).unwrap(),
+            )
+            .unwrap()
+            .with_default(Some("base".to_string()));
+        assert_eq!(
+            map.get("mozc", ImeContext::default()).as_deref(),
+            Some("oyayubi")
+        );
+        assert_eq!(
+            map.get("mozc", app("kitty")).as_deref(),
+            Some("oyayubi-term")
+        );
+        assert_eq!(map.get("mozc", app("firefox")).as_deref(), Some("oyayubi"));
+        // アプリケーションのマップに一致しない場合は全体のマップを使う。
+        assert_eq!(map.get("hangul", app("kitty")).as_deref(), Some("base"));
+        assert!(map.is_ignored("mozc", app("steam_app_1234")));
+        assert_eq!(
+            map.fixed_values().collect::<Vec<_>>(),
+            vec!["oyayubi", "base", "oyayubi-term"]
+        );
+    }
+
+    #[test]
+    fn language_rules() {
+        let map = layer_map(&[
+            ("xkb:*", "base"),
+            ("lang:ja", "ja"),
+            ("lang:zh_TW", "zhuyin"),
+        ])
+        .with_ignored(["lang:ko".to_string()])
+        .unwrap()
+        .with_default(Some("us".to_string()));
+        assert!(map.has_language_rules());
+        assert_eq!(map.get("anthy", language("ja")).as_deref(), Some("ja"));
+        assert_eq!(map.get("kkc", language("ja_JP")).as_deref(), Some("ja"));
+        assert_eq!(
+            map.get("chewing", language("zh_TW")).as_deref(),
+            Some("zhuyin")
+        );
+        assert_eq!(map.get("pinyin", language("zh_CN")).as_deref(), Some("us"));
+        assert_eq!(
+            map.get("xkb:jp::jpn", language("ja")).as_deref(),
+            Some("base")
+        );
+        // `ja`は`jav`に一致しない。
+        assert_eq!(map.get("javanese", language("jav")).as_deref(), Some("us"));
+        assert_eq!(
+            map.get("anthy", ImeContext::default()).as_deref(),
+            Some("us")
+        );
+        assert!(map.is_ignored("hangul", language("ko")));
+        assert!(!layer_map(&[("mozc", "ja")]).has_language_rules());
+        assert!(
+            layer_map(&[])
+                .with_app("lang:ja".to_string(), layer_map(&[]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn field_rules() {
+        let map = layer_map(&[
+            ("layout=us,variant=", "us"),
+            ("layout=us", "us-variant"),
+            ("mode=Romaji*", "romaji"),
+        ]);
+        assert_eq!(
+            map.get("xkb:us::eng", ImeContext::default()).as_deref(),
+            Some("us")
+        );
+        assert_eq!(
+            map.get("keyboard-us-intl", ImeContext::default())
+                .as_deref(),
+            Some("us-variant")
+        );
+        assert_eq!(
+            map.get(
+                "com.apple.inputmethod.Kotoeri.RomajiTyping.Japanese",
+                ImeContext::default()
+            )
+            .as_deref(),
+            Some("romaji")
+        );
+        assert_eq!(map.get("mozc-jp", ImeContext::default()), None);
+        assert!(ImeMap::new([("size=big".to_string(), 0)]).is_err());
+        assert!(ImeMap::new([("layout=us,intl".to_string(), 0)]).is_err());
+    }
+
+    #[test]
+    fn alias_has_priority() {
+        let map = layer_map(&[("japanese", "ja"), ("mozc-jp", "mozc"), ("xkb:*", "base")])
+            .with_app("kitty".to_string(), layer_map(&[("mozc-jp", "mozc-term")]))
+            .unwrap();
+        let context = |alias| ImeContext {
+            alias: Some(alias),
+            ..Default::default()
+        };
+        assert_eq!(
+            map.get("mozc-jp", context("japanese")).as_deref(),
+            Some("ja")
+        );
+        // 別名に一致しない場合はIME名を使う。
+        assert_eq!(
+            map.get("xkb:us::eng", context("us")).as_deref(),
+            Some("base")
+        );
+        // アプリケーションのマップのIME名は全体のマップの別名より優先する。
+        assert_eq!(
+            map.get(
+                "mozc-jp",
+                ImeContext {
+                    app_name: Some("kitty"),
+                    ..context("japanese")
+                }
+            )
+            .as_deref(),
+            Some("mozc-term")
+        );
+        // IME名の完全一致は別名のパターンより優先する。
+        let map = layer_map(&[("jap*", "ja"), ("mozc-jp", "mozc")]);
+        assert_eq!(
+            map.get("mozc-jp", context("japanese")).as_deref(),
+            Some("mozc")
+        );
+        assert_eq!(map.get("anthy", context("japanese")).as_deref(), Some("ja"));
+    }
+
+    #[test]
+    fn invalid_or_duplicate_is_error() {
+        assert!(ImeMap::new([("re:(".to_string(), 0)]).is_err());
+        assert!(ImeMap::new([("mozc".to_string(), 0), ("mozc".to_string(), 1)]).is_err());
+        assert!(ImeMap::new([("xkb:*".to_string(), 0), ("xkb:*".to_string(), 1)]).is_err());
+        assert!(
+            layer_map(&[])
+                .with_app("kitty".to_string(), layer_map(&[]))
+                .unwrap()
+                .with_app("kitty".to_string(), layer_map(&[]))
+                .is_err()
+        );
+    }
+}
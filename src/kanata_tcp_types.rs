@@ -1,13 +1,217 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Debug)]
+/// kanataのリクエストに対する応答。
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct KanataServerResponse {
-    pub status: String,
+    pub status: KanataResponseStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub msg: Option<String>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KanataResponseStatus {
+    Ok,
+    Error,
+}
+
+/// kanataから受信するメッセージ。kanataのTCPサーバーのプロトコルに対応する。
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum KanataServerMessage {
+    LayerChange {
+        new: String,
+    },
+    LayerNames {
+        names: Vec<String>,
+    },
+    CurrentLayerInfo {
+        name: String,
+        cfg_text: String,
+    },
+    CurrentLayerName {
+        name: String,
+    },
+    ConfigFileReload {
+        new: String,
+    },
+    MessagePush {
+        message: serde_json::Value,
+    },
+    Error {
+        msg: String,
+    },
+    /// `{"status": ...}`の形式の応答。
+    #[serde(untagged)]
+    Response(KanataServerResponse),
+}
+
+/// kanataへ送信するメッセージ。kanataのTCPクライアントのプロトコルに対応する。
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum KanataClientMessage {
     ChangeLayer { new: String },
+    RequestLayerNames {},
+    RequestCurrentLayerName {},
+    RequestCurrentLayerInfo {},
+    ActOnFakeKey { name: String, action: FakeKeyAction },
+    SetMouse { x: u16, y: u16 },
+    Reload {},
+    ReloadNext {},
+    ReloadPrev {},
     ReloadNum { index: usize },
+    ReloadFile { path: String },
+}
+
+/// ActOnFakeKeyで仮想キーに行う操作。
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FakeKeyAction {
+    Press,
+    Release,
+    Tap,
+    Toggle,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(msg: KanataClientMessage, json: &str) {
+        assert_eq!(serde_json::to_string(&msg).unwrap(), json);
+        assert_eq!(
+            serde_json::from_str::<KanataClientMessage>(json).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn client_message_round_trip() {
+        round_trip(
+            KanataClientMessage::ChangeLayer {
+                new: "base".to_string(),
+            },
+            r#"{"ChangeLayer":{"new":"base"}}"#,
+        );
+        round_trip(
+            KanataClientMessage::RequestLayerNames {},
+            r#"{"RequestLayerNames":{}}"#,
+        );
+        round_trip(
+            KanataClientMessage::RequestCurrentLayerName {},
+            r#"{"RequestCurrentLayerName":{}}"#,
+        );
+        round_trip(
+            KanataClientMessage::RequestCurrentLayerInfo {},
+            r#"{"RequestCurrentLayerInfo":{}}"#,
+        );
+        round_trip(
+            KanataClientMessage::ActOnFakeKey {
+                name: "ime-ja".to_string(),
+                action: FakeKeyAction::Tap,
+            },
+            r#"{"ActOnFakeKey":{"name":"ime-ja","action":"Tap"}}"#,
+        );
+        round_trip(
+            KanataClientMessage::SetMouse { x: 10, y: 20 },
+            r#"{"SetMouse":{"x":10,"y":20}}"#,
+        );
+        round_trip(KanataClientMessage::Reload {}, r#"{"Reload":{}}"#);
+        round_trip(KanataClientMessage::ReloadNext {}, r#"{"ReloadNext":{}}"#);
+        round_trip(KanataClientMessage::ReloadPrev {}, r#"{"ReloadPrev":{}}"#);
+        round_trip(
+            KanataClientMessage::ReloadNum { index: 1 },
+            r#"{"ReloadNum":{"index":1}}"#,
+        );
+        round_trip(
+            KanataClientMessage::ReloadFile {
+                path: "normal.kbd".to_string(),
+            },
+            r#"{"ReloadFile":{"path":"normal.kbd"}}"#,
+        );
+    }
+
+    fn server_round_trip(msg: KanataServerMessage, json: &str) {
+        assert_eq!(serde_json::to_string(&msg).unwrap(), json);
+        assert_eq!(
+            serde_json::from_str::<KanataServerMessage>(json).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn server_message_round_trip() {
+        server_round_trip(
+            KanataServerMessage::LayerChange {
+                new: "base".to_string(),
+            },
+            r#"{"LayerChange":{"new":"base"}}"#,
+        );
+        server_round_trip(
+            KanataServerMessage::LayerNames {
+                names: vec!["base".to_string(), "oyayubi-shift".to_string()],
+            },
+            r#"{"LayerNames":{"names":["base","oyayubi-shift"]}}"#,
+        );
+        server_round_trip(
+            KanataServerMessage::CurrentLayerInfo {
+                name: "base".to_string(),
+                cfg_text: "(deflayer base a)".to_string(),
+            },
+            r#"{"CurrentLayerInfo":{"name":"base","cfg_text":"(deflayer base a)"}}"#,
+        );
+        server_round_trip(
+            KanataServerMessage::CurrentLayerName {
+                name: "base".to_string(),
+            },
+            r#"{"CurrentLayerName":{"name":"base"}}"#,
+        );
+        server_round_trip(
+            KanataServerMessage::ConfigFileReload {
+                new: "normal.kbd".to_string(),
+            },
+            r#"{"ConfigFileReload":{"new":"normal.kbd"}}"#,
+        );
+        server_round_trip(
+            KanataServerMessage::MessagePush {
+                message: serde_json::json!(["push", 1]),
+            },
+            r#"{"MessagePush":{"message":["push",1]}}"#,
+        );
+        server_round_trip(
+            KanataServerMessage::Error {
+                msg: "unknown layer".to_string(),
+            },
+            r#"{"Error":{"msg":"unknown layer"}}"#,
+        );
+        server_round_trip(
+            KanataServerMessage::Response(KanataServerResponse {
+                status: KanataResponseStatus::Ok,
+                msg: None,
+            }),
+            r#"{"status":"Ok"}"#,
+        );
+        server_round_trip(
+            KanataServerMessage::Response(KanataServerResponse {
+                status: KanataResponseStatus::Error,
+                msg: Some("unknown layer".to_string()),
+            }),
+            r#"{"status":"Error","msg":"unknown layer"}"#,
+        );
+    }
+
+    #[test]
+    fn unknown_server_message_is_error() {
+        assert!(serde_json::from_str::<KanataServerMessage>(r#"{"Unknown":{}}"#).is_err());
+        assert!(serde_json::from_str::<KanataServerMessage>(r#"{"status":"Unknown"}"#).is_err());
+    }
+
+    #[test]
+    fn fake_key_action_round_trip() {
+        for (action, json) in [
+            (FakeKeyAction::Press, r#""Press""#),
+            (FakeKeyAction::Release, r#""Release""#),
+            (FakeKeyAction::Tap, r#""Tap""#),
+            (FakeKeyAction::Toggle, r#""Toggle""#),
+        ] {
+            assert_eq!(serde_json::to_string(&action).unwrap(), json);
+            assert_eq!(serde_json::from_str::<FakeKeyAction>(json).unwrap(), action);
+        }
+    }
 }
@@ -1,11 +1,22 @@
 pub mod args;
+pub mod backend;
 mod error;
+pub mod ime_alias;
+pub mod ime_id;
+pub mod ime_map;
 pub mod kanata_tcp_types;
+pub mod script;
+pub mod settings;
+pub mod transport;
+pub mod watch;
 
-#[cfg(all(feature = "fcitx", target_os = "linux"))]
+#[cfg(target_os = "linux")]
 pub mod fcitx;
 
-#[cfg(all(not(feature = "fcitx"), target_os = "linux"))]
+#[cfg(target_os = "linux")]
+pub mod focus;
+
+#[cfg(target_os = "linux")]
 pub mod ibus;
 
 #[cfg(all(feature = "winonoff", target_os = "windows"))]
@@ -19,9 +30,13 @@ pub mod mac;
 
 pub use error::AppError;
 
-use std::collections::HashMap;
+use ime_map::{ImeContext, ImeMap};
+use kanata_tcp_types::{FakeKeyAction, KanataServerMessage};
+use transport::KanataAddress;
+
 use std::sync::Arc;
 use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::time::Duration;
 
 use log::{debug, error};
 use once_cell::sync::OnceCell;
@@ -30,9 +45,6 @@ use once_cell::sync::OnceCell;
 pub type FatalErrorReceiver = Receiver<AppError>;
 pub type FatalErrorSender = SyncSender<AppError>;
 
-/// 致命的なエラーを検知するためのグローバルセンダー。
-pub static FATAL_ERROR_SENDER: OnceCell<FatalErrorSender> = OnceCell::new();
-
 /// ime情報を取得するタイミングを通知するためのメッセージ。
 pub enum Message {
     GetImeStatus,
@@ -44,29 +56,47 @@ pub enum Message {
 pub type MessageReceiver = Receiver<Message>;
 pub type MessageSender = SyncSender<Message>;
 
-/// ime情報を取得するタイミングを通知するためのメッセージのグローバルセンダー。
-pub static MESSAGE_SENDER: OnceCell<MessageSender> = OnceCell::new();
-
-/// アプリケーションの最初に呼ぶ．
-pub fn initialize_app() -> Result<(MessageReceiver, FatalErrorReceiver), AppError> {
-    let (get_ime_status_message_sender, get_ime_status_message_receiver) = sync_channel(1);
-    MESSAGE_SENDER
-        .set(get_ime_status_message_sender)
-        .map_err(|_| {
-            AppError::CustomError("Internal bug. initialized multiple times.".to_string())
-        })?;
+/// 監視のインスタンスごとのコンテキスト。メッセージと致命的なエラーのセンダーを保持する。
+/// メインループ・レシーバー・コールバックなどに引数として渡す。
+#[derive(Clone)]
+pub struct AppContext {
+    message_sender: MessageSender,
+    fatal_error_sender: FatalErrorSender,
+}
 
+/// 監視のインスタンスの最初に呼ぶ．インスタンスごとに何度でも呼べる．
+pub fn initialize_app() -> (AppContext, MessageReceiver, FatalErrorReceiver) {
+    let (message_sender, message_receiver) = sync_channel(1);
     let (fatal_error_sender, fatal_error_receiver) = sync_channel(1);
-    FATAL_ERROR_SENDER.set(fatal_error_sender).map_err(|_| {
-        AppError::CustomError("Internal bug. initialized multiple times.".to_string())
-    })?;
 
-    Ok((get_ime_status_message_receiver, fatal_error_receiver))
+    let context = AppContext {
+        message_sender,
+        fatal_error_sender,
+    };
+
+    (context, message_receiver, fatal_error_receiver)
 }
 
 /// ime情報を受け渡すためのレシーバー
 pub type InnerReceiver = Receiver<String>;
 
+/// kanataへのリクエストを決定するスレッドへ送るイベント。
+pub enum ObserverEvent {
+    /// IMEの状態の変化。
+    ImeStatus(String),
+    /// kanataから受信したメッセージ。
+    Kanata(KanataServerMessage),
+    /// 設定ファイルから読み込み直したコマンド。
+    Reload(Arc<Command>),
+    /// フォーカス中のアプリケーションの変化。
+    Focus(Option<String>),
+    CaughtFatalError,
+}
+
+/// kanataへのリクエストを決定するスレッドへイベントを送るためのレシーバー・センダー。
+pub type EventReceiver = Receiver<ObserverEvent>;
+pub type EventSender = SyncSender<ObserverEvent>;
+
 /// 致命的なエラー。全てのスレッドを終了し再接続を試みる。
 pub struct FatalError(Arc<OnceCell<AppError>>);
 
@@ -91,28 +121,29 @@ impl Clone for FatalError {
     }
 }
 
-/// catch_fatal_errorされているFatalErrorにグローバルセンダーを通してエラーを送信する．
-pub fn send_fatal_error(err: AppError) {
-    if let Some(sender) = FATAL_ERROR_SENDER.get() {
-        if let Err(e) = sender.try_send(err) {
-            match e {
-                TrySendError::Disconnected(_) => {
-                    error!("Internal bug. FATAL_ERROR_SENDER was disconnected. : {e}")
-                }
-                TrySendError::Full(_) => debug!("FATAL_ERROR_SENDER is full. :{e}"),
+/// catch_fatal_errorされているFatalErrorにコンテキストのセンダーを通してエラーを送信する．
+pub fn send_fatal_error(context: &AppContext, err: AppError) {
+    if let Err(e) = context.fatal_error_sender.try_send(err) {
+        match e {
+            TrySendError::Disconnected(_) => {
+                error!("Internal bug. fatal_error_sender was disconnected. : {e}")
             }
+            TrySendError::Full(_) => debug!("fatal_error_sender is full. :{e}"),
         }
-    } else {
-        error!("Internal bug. Set the FATAL_ERROR_SENDER");
     }
 }
 
 /// 内部Senderのエラーハンドリング
-pub fn handle_try_send<T>(sender: &SyncSender<T>, value: T, sender_name: String) {
+pub fn handle_try_send<T>(
+    context: &AppContext,
+    sender: &SyncSender<T>,
+    value: T,
+    sender_name: String,
+) {
     if let Err(e) = sender.try_send(value) {
         match e {
             TrySendError::Disconnected(_) => {
-                send_fatal_error(AppError::InnerSenderError { sender_name });
+                send_fatal_error(context, AppError::InnerSenderError { sender_name });
             }
             TrySendError::Full(_) => log::debug!("{e}"),
         }
@@ -139,21 +170,218 @@ pub fn initialize_fatal_error(fatal_error_receiver: &FatalErrorReceiver) -> Fata
     FatalError::new()
 }
 
-/// グローバルセンダーを通したメッセージの送信。
-pub fn send_message(message: Message) {
-    if let Some(message_sender) = MESSAGE_SENDER.get() {
-        handle_try_send(message_sender, message, "MESSAGE_SENDER".to_string());
-    } else {
-        send_fatal_error(AppError::CustomError(
-            "GET_IME_STATUS_MESSAGE_SENDER".to_owned(),
-        ));
+/// コンテキストのセンダーを通したメッセージの送信。
+pub fn send_message(context: &AppContext, message: Message) {
+    handle_try_send(
+        context,
+        &context.message_sender,
+        message,
+        "message_sender".to_string(),
+    );
+}
+
+/// コンテキストのセンダーを通したメッセージの送信。受信されるまでブロッキングする。
+pub fn send_message_blocking(context: &AppContext, message: Message) {
+    if context.message_sender.send(message).is_err() {
+        send_fatal_error(
+            context,
+            AppError::InnerSenderError {
+                sender_name: "message_sender".to_string(),
+            },
+        );
     }
 }
 
+/// 設定ファイルのポーリング間隔を読み込む。0はポーリングしないことを表す。
+#[cfg(target_os = "windows")]
+pub fn deserialize_polling_span<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+
+    let polling_span = u64::deserialize(deserializer)?;
+    Ok((polling_span != 0).then_some(polling_span))
+}
+
+/// IME情報を受け取るレシーバー。各バックエンドが実装する。
+pub trait ImeReceiver: Sized {
+    /// バックエンドごとの設定。
+    type Config: Default + std::fmt::Debug;
+
+    /// ワーカースレッドなどを起動する。メインループごとに呼ぶ。
+    fn new(
+        context: &AppContext,
+        message_receiver: MessageReceiver,
+        config: &Self::Config,
+        fatal_error: &FatalError,
+    ) -> Result<Self, AppError>;
+
+    /// IMEの状態が変化するまでブロッキングし、変化後のIMEの状態を返す。
+    fn receive(&mut self) -> Result<String, AppError>;
+
+    /// ワーカースレッドを終了し、次のループで利用するためにメッセージのレシーバーを返す。
+    fn shutdown(self) -> MessageReceiver;
+
+    /// switch_imeに対応するかどうか。
+    const CAN_SWITCH_IME: bool = false;
+
+    /// IMEを切り替える。kanataのレイヤーの変化に合わせるために利用する。
+    fn switch_ime(ime_status: &str) -> Result<(), AppError> {
+        Err(AppError::CustomError(format!(
+            "This backend cannot switch the IME to \"{ime_status}\"."
+        )))
+    }
+
+    /// IMEの言語(`ja`・`zh_CN`など)。言語を取得できないバックエンドはNoneを返す。
+    fn ime_language(_ime_status: &str) -> Option<String> {
+        None
+    }
+}
+
+/// IMEの変化を検知してメッセージを送信するメインループ。各バックエンドが実装する。
+pub trait ImeMainLoop: ImeReceiver {
+    /// メインスレッドで呼ぶ。FatalErrorを検知するまでブロッキングする。
+    fn main_loop(
+        context: &AppContext,
+        config: &Self::Config,
+        fatal_error: &FatalError,
+    ) -> Result<(), AppError>;
+}
+
 /// cli用のコマンド。
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Command {
-    Config(HashMap<String, usize>),
-    Layer(HashMap<String, String>),
+    Config(ImeMap<usize>),
+    ConfigFile(ImeMap<String>),
+    Layer(ImeMap<String>),
+    /// IME名から仮想キー名へのマップと、IMEに入る際に行う操作。
+    FakeKey {
+        key_map: ImeMap<String>,
+        action: FakeKeyAction,
+    },
     Log,
 }
+
+impl Command {
+    /// フォーカス中のアプリケーションによって値が変わるかどうか。
+    pub fn has_app_rules(&self) -> bool {
+        match self {
+            Command::Config(ime_map) => ime_map.has_app_rules(),
+            Command::ConfigFile(ime_map)
+            | Command::Layer(ime_map)
+            | Command::FakeKey {
+                key_map: ime_map, ..
+            } => ime_map.has_app_rules(),
+            Command::Log => false,
+        }
+    }
+
+    /// IMEの言語によって値が変わるかどうか。
+    pub fn has_language_rules(&self) -> bool {
+        match self {
+            Command::Config(ime_map) => ime_map.has_language_rules(),
+            Command::ConfigFile(ime_map)
+            | Command::Layer(ime_map)
+            | Command::FakeKey {
+                key_map: ime_map, ..
+            } => ime_map.has_language_rules(),
+            Command::Log => false,
+        }
+    }
+
+    /// 条件の変化でIMEに対応する値が変わるかどうか。
+    pub fn changes_with(
+        &self,
+        ime_name: &str,
+        pre_context: ImeContext,
+        context: ImeContext,
+    ) -> bool {
+        match self {
+            Command::Config(ime_map) => {
+                ime_map.get(ime_name, pre_context) != ime_map.get(ime_name, context)
+            }
+            Command::ConfigFile(ime_map)
+            | Command::Layer(ime_map)
+            | Command::FakeKey {
+                key_map: ime_map, ..
+            } => ime_map.get(ime_name, pre_context) != ime_map.get(ime_name, context),
+            Command::Log => false,
+        }
+    }
+}
+
+/// kanataのレイヤーがIMEに対応するレイヤーから外れた場合の方針。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LayerPolicy {
+    /// kanataのレイヤーの変化に従う。
+    #[default]
+    Follow,
+    /// 猶予期間の後、IMEに対応するレイヤーに戻す。
+    Enforce { grace: Duration },
+    /// Followに加えて、IMEごとに最後のレイヤーを記憶し、IMEに戻った際に復元する。
+    Remember,
+}
+
+/// kanataの接続先とそのコマンド。
+#[derive(Debug)]
+pub struct Target {
+    pub address: KanataAddress,
+    pub command: Command,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances_do_not_share_senders() {
+        let (context_a, message_receiver_a, fatal_error_receiver_a) = initialize_app();
+        let (context_b, message_receiver_b, fatal_error_receiver_b) = initialize_app();
+
+        send_message(&context_a, Message::ImeStatus("mozc".to_string()));
+        assert!(matches!(
+            message_receiver_a.try_recv(),
+            Ok(Message::ImeStatus(ime_status)) if ime_status == "mozc"
+        ));
+        assert!(message_receiver_b.try_recv().is_err());
+
+        let fatal_error_b = initialize_fatal_error(&fatal_error_receiver_b);
+        send_fatal_error(&context_b, AppError::CustomError("b".to_string()));
+        catch_fatal_error(fatal_error_b.clone(), &fatal_error_receiver_b);
+        assert!(!fatal_error_b.is_none());
+        assert!(fatal_error_receiver_a.try_recv().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn receiver_can_be_created_and_shutdown_repeatedly() {
+        use crate::ibus::{IbusImeReceiver, IbusImeReceiverConfig};
+
+        for _ in 0..3 {
+            let (context, message_receiver, fatal_error_receiver) = initialize_app();
+            let fatal_error = initialize_fatal_error(&fatal_error_receiver);
+
+            let mut receiver = IbusImeReceiver::new(
+                &context,
+                message_receiver,
+                &IbusImeReceiverConfig::default(),
+                &fatal_error,
+            )
+            .unwrap();
+
+            send_message(&context, Message::ImeStatus("mozc-jp".to_string()));
+            assert_eq!(receiver.receive().unwrap(), "mozc-jp");
+
+            // shutdownで送られたエラーをcatchし、ワーカーのrecvを解除する。
+            let catch_handle = std::thread::spawn({
+                let fatal_error = fatal_error.clone();
+                move || catch_fatal_error(fatal_error, &fatal_error_receiver)
+            });
+            let shutdown_handle = std::thread::spawn(move || receiver.shutdown());
+            catch_handle.join().unwrap();
+            send_message(&context, Message::CaughtFatalError);
+            shutdown_handle.join().unwrap();
+        }
+    }
+}
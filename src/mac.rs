@@ -1,6 +1,6 @@
 use crate::{
-    AppError, FatalError, InnerReceiver, Message, MessageReceiver, handle_try_send,
-    send_fatal_error, send_message,
+    AppContext, AppError, FatalError, ImeMainLoop, ImeReceiver, InnerReceiver, Message,
+    MessageReceiver, handle_try_send, send_fatal_error, send_message,
 };
 
 use std::ffi::c_void;
@@ -19,6 +19,7 @@ use core_foundation_sys::notification_center::{
     CFNotificationSuspensionBehavior,
 };
 use log::{debug, error};
+use serde::Deserialize;
 
 type TISInputSourceRef = *const c_void;
 
@@ -38,15 +39,16 @@ unsafe extern "C" {
     static kTISNotifySelectedKeyboardInputSourceChanged: CFStringRef;
 }
 
-/// 入力ソースが変更されるごとに呼ばれるコールバック
+/// 入力ソースが変更されるごとに呼ばれるコールバック。observerにはAppContextのポインタを渡す。
 extern "C" fn callback(
     _center: CFNotificationCenterRef,
-    _observer: *mut c_void,
+    observer: *mut c_void,
     _name: CFNotificationName,
     _object: *const c_void,
     _user_info: CFDictionaryRef,
 ) {
-    send_message(Message::GetImeStatus);
+    let context = unsafe { &*(observer as *const AppContext) };
+    send_message(context, Message::GetImeStatus);
 }
 
 fn get_current_input_source() -> Result<String, AppError> {
@@ -70,9 +72,9 @@ fn get_current_input_source() -> Result<String, AppError> {
 }
 
 /// macのメインループ
-pub fn mac_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
+pub fn mac_main_loop(context: &AppContext, fatal_error: &FatalError) -> Result<(), AppError> {
     unsafe {
-        let observer_ptr = Box::into_raw(Box::new(1)); // observer自体はなんでも良い
+        let observer_ptr = Box::into_raw(Box::new(context.clone())); // コールバックで利用する
 
         let notify_center = CFNotificationCenterGetDistributedCenter();
 
@@ -85,6 +87,8 @@ pub fn mac_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
             CFNotificationSuspensionBehaviorDeliverImmediately,
         );
 
+        send_message(context, Message::GetImeStatus); // 起動時の状態を取得する。
+
         // run_loop
         while let run_result =
             CFRunLoop::run_in_mode(kCFRunLoopDefaultMode, Duration::from_secs(1), true)
@@ -113,7 +117,8 @@ pub fn mac_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct MacImeReceiverConfig {
     pub delay: u64,
 }
@@ -125,13 +130,17 @@ impl Default for MacImeReceiverConfig {
 }
 
 pub struct MacImeReceiver {
+    context: AppContext,
     _worker_handle: std::thread::JoinHandle<MessageReceiver>,
     inner_receiver: InnerReceiver,
     pre_ime_status: Option<String>,
 }
 
-impl MacImeReceiver {
-    pub fn new(
+impl ImeReceiver for MacImeReceiver {
+    type Config = MacImeReceiverConfig;
+
+    fn new(
+        context: &AppContext,
         message_receiver: MessageReceiver,
         config: &MacImeReceiverConfig,
         fatal_error: &FatalError,
@@ -141,6 +150,7 @@ impl MacImeReceiver {
         let (inner_sender, inner_receiver) = sync_channel(1);
 
         let _worker_handle = std::thread::spawn({
+            let context = context.clone();
             let fatal_error = fatal_error.clone();
             let delay = *delay;
 
@@ -152,6 +162,7 @@ impl MacImeReceiver {
 
                     match get_current_input_source() {
                         Ok(ime_status) => handle_try_send(
+                            &context,
                             &inner_sender,
                             ime_status,
                             "MacImeReceiver inner sender".to_string(),
@@ -167,13 +178,14 @@ impl MacImeReceiver {
         });
 
         Ok(Self {
+            context: context.clone(),
             _worker_handle,
             inner_receiver,
             pre_ime_status: None,
         })
     }
 
-    pub fn receive(&mut self) -> Result<String, AppError> {
+    fn receive(&mut self) -> Result<String, AppError> {
         loop {
             let new_ime_status =
                 self.inner_receiver
@@ -194,11 +206,24 @@ impl MacImeReceiver {
         }
     }
 
-    pub fn shutdown(self) -> MessageReceiver {
-        send_fatal_error(AppError::CustomError("Receiver shutdown.".to_string()));
+    fn shutdown(self) -> MessageReceiver {
+        send_fatal_error(
+            &self.context,
+            AppError::CustomError("Receiver shutdown.".to_string()),
+        );
         let message_receiver = self._worker_handle.join().expect("worker thread panicked.");
         debug!("MacImeReceiver shutdown.");
 
         message_receiver
     }
 }
+
+impl ImeMainLoop for MacImeReceiver {
+    fn main_loop(
+        context: &AppContext,
+        _config: &Self::Config,
+        fatal_error: &FatalError,
+    ) -> Result<(), AppError> {
+        mac_main_loop(context, fatal_error)
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{
+    AppContext, AppError, FatalError, ImeMainLoop, ImeReceiver, InnerReceiver, MessageReceiver,
+    send_fatal_error,
+};
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SendError, SyncSender, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error, info};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+/// IME名を読み込む入力元。
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(from = "String")]
+pub enum ScriptSource {
+    /// 標準入力。
+    #[default]
+    Stdin,
+    /// ファイルまたはFIFO。
+    Path(PathBuf),
+}
+
+impl From<&str> for ScriptSource {
+    fn from(value: &str) -> Self {
+        match value {
+            "-" => ScriptSource::Stdin,
+            path => ScriptSource::Path(PathBuf::from(path)),
+        }
+    }
+}
+
+impl From<String> for ScriptSource {
+    fn from(value: String) -> Self {
+        ScriptSource::from(value.as_str())
+    }
+}
+
+/// EOFで終了する場合に、最後の行のリクエストがkanataへ届くまで待つ時間。
+const EXIT_DELAY: Duration = Duration::from_millis(500);
+
+/// FIFOの場合は書き込み側が閉じても開き直す。
+#[cfg(unix)]
+fn is_fifo(file: &File) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    file.metadata()
+        .map(|metadata| metadata.file_type().is_fifo())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_file: &File) -> bool {
+    false
+}
+
+/// 1行ずつIME名を送信する。空行は無視する。行を失わないようにブロッキングして送信する。
+fn send_lines(sender: &SyncSender<String>, reader: impl BufRead) -> Result<(), AppError> {
+    for line in reader.lines() {
+        let line = line?;
+        let ime_status = line.trim();
+        if !ime_status.is_empty() && sender.send(ime_status.to_string()).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// ファイル・FIFOから読み込む。FIFOは書き込み側が閉じると開き直し、通常のファイルは末尾で終了する。
+fn read_path(sender: &SyncSender<String>, path: &Path) -> Result<(), AppError> {
+    loop {
+        let file = File::open(path)?;
+        let fifo = is_fifo(&file);
+
+        send_lines(sender, BufReader::new(file))?;
+
+        if !fifo {
+            info!("Reached the end of '{}'.", path.display());
+            return Ok(());
+        }
+        debug!("Reopen the FIFO '{}'.", path.display());
+    }
+}
+
+/// 読み込みスレッドから受け取る行。pendingはshutdownで送れなかった行で、次のレシーバーが最初に送る。
+#[derive(Debug)]
+struct ScriptLines {
+    receiver: Receiver<String>,
+    pending: Option<String>,
+}
+
+/// 入力元を読み込むスレッドの状態。メインループの再起動をまたいで1つだけ起動し、
+/// 再起動後のレシーバーは同じ行の続きを受け取る。
+#[derive(Debug)]
+pub struct ScriptReader {
+    lines: Mutex<Option<ScriptLines>>,
+    end: Mutex<Option<AppError>>,
+}
+
+impl ScriptReader {
+    fn spawn(source: &ScriptSource, exit_at_eof: bool) -> Result<Arc<Self>, AppError> {
+        // ファイルが存在しない場合はアプリを終了する。FIFOはopenでブロッキングするため、ここでは開かない。
+        if let ScriptSource::Path(path) = source {
+            std::fs::metadata(path)?;
+        }
+
+        // 受け取られるまでブロッキングし、読み込みが先に進まないようにする。
+        let (sender, receiver) = sync_channel(0);
+        let reader = Arc::new(Self {
+            lines: Mutex::new(Some(ScriptLines {
+                receiver,
+                pending: None,
+            })),
+            end: Mutex::new(None),
+        });
+
+        // 読み込みはブロッキングするため別スレッドで行う。
+        std::thread::spawn({
+            let reader = Arc::clone(&reader);
+            let source = source.clone();
+
+            move || {
+                let result = match &source {
+                    ScriptSource::Stdin => {
+                        send_lines(&sender, std::io::stdin().lock()).inspect(|_| {
+                            info!("Reached the end of stdin.");
+                        })
+                    }
+                    ScriptSource::Path(path) => read_path(&sender, path),
+                };
+
+                let end = match result {
+                    Ok(()) if exit_at_eof => {
+                        std::thread::sleep(EXIT_DELAY);
+                        AppError::ScriptEnd
+                    }
+                    Ok(()) => return,
+                    Err(e) => {
+                        error!("Failed to read the script: {e}");
+                        e
+                    }
+                };
+                *reader
+                    .end
+                    .lock()
+                    .expect("script reader mutex was poisoned.") = Some(end);
+            }
+        });
+
+        Ok(reader)
+    }
+}
+
+/// scriptのメインループ。読み込みが終了した場合はアプリを終了する。
+pub fn script_main_loop(
+    config: &ScriptImeReceiverConfig,
+    fatal_error: &FatalError,
+) -> Result<(), AppError> {
+    let reader = config.reader()?;
+
+    // メインループ
+    while fatal_error.is_none() {
+        if let Some(e) = reader
+            .end
+            .lock()
+            .expect("script reader mutex was poisoned.")
+            .take()
+        {
+            return Err(e);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    Err(AppError::CaughtFatalError {
+        location: "script_main_loop".to_string(),
+    })
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptImeReceiverConfig {
+    pub source: ScriptSource,
+    /// 標準入力・通常のファイルの末尾でアプリを終了する。
+    pub exit_at_eof: bool,
+    #[serde(skip)]
+    reader: OnceCell<Arc<ScriptReader>>,
+}
+
+impl ScriptImeReceiverConfig {
+    /// 読み込みスレッド。最初に呼ばれた際に起動する。
+    fn reader(&self) -> Result<&Arc<ScriptReader>, AppError> {
+        self.reader
+            .get_or_try_init(|| ScriptReader::spawn(&self.source, self.exit_at_eof))
+    }
+}
+
+pub struct ScriptImeReceiver {
+    context: AppContext,
+    _worker_handle: std::thread::JoinHandle<()>,
+    message_receiver: MessageReceiver,
+    inner_receiver: InnerReceiver,
+    pre_ime_status: Option<String>,
+}
+
+impl ImeReceiver for ScriptImeReceiver {
+    type Config = ScriptImeReceiverConfig;
+
+    fn new(
+        context: &AppContext,
+        message_receiver: MessageReceiver,
+        config: &ScriptImeReceiverConfig,
+        fatal_error: &FatalError,
+    ) -> Result<Self, AppError> {
+        let reader = Arc::clone(config.reader()?);
+
+        // 受け取られるまでブロッキングし、shutdownで行を失わないようにする。
+        let (inner_sender, inner_receiver) = sync_channel(0);
+
+        let _worker_handle = std::thread::spawn({
+            let fatal_error = fatal_error.clone();
+
+            move || {
+                let mut lines = reader
+                    .lines
+                    .lock()
+                    .expect("script reader mutex was poisoned.")
+                    .take()
+                    .expect("the previous ScriptImeReceiver was not shut down.");
+
+                while fatal_error.is_none() {
+                    let line = match lines.pending.take() {
+                        Some(line) => line,
+                        None => match lines.receiver.recv_timeout(Duration::from_millis(100)) {
+                            Ok(line) => line,
+                            Err(RecvTimeoutError::Timeout) => continue,
+                            Err(RecvTimeoutError::Disconnected) => {
+                                // 読み込みが終了した。FatalErrorまで待つ。
+                                std::thread::sleep(Duration::from_millis(100));
+                                continue;
+                            }
+                        },
+                    };
+                    // shutdownでinner_receiverがドロップされると解除される。
+                    if let Err(SendError(line)) = inner_sender.send(line) {
+                        lines.pending = Some(line);
+                        break;
+                    }
+                }
+
+                *reader
+                    .lines
+                    .lock()
+                    .expect("script reader mutex was poisoned.") = Some(lines);
+            }
+        });
+
+        Ok(Self {
+            context: context.clone(),
+            _worker_handle,
+            message_receiver,
+            inner_receiver,
+            pre_ime_status: None,
+        })
+    }
+    fn receive(&mut self) -> Result<String, AppError> {
+        loop {
+            // ワーカーはFatalErrorでのみ終了する。
+            let new_ime_status =
+                self.inner_receiver
+                    .recv()
+                    .map_err(|_| AppError::CaughtFatalError {
+                        location: "ScriptImeReceiver".to_string(),
+                    })?;
+
+            if let Some(pre_ime_status) = &self.pre_ime_status {
+                if *pre_ime_status != new_ime_status {
+                    self.pre_ime_status = Some(new_ime_status.clone());
+                    return Ok(new_ime_status);
+                }
+            } else {
+                self.pre_ime_status = Some(new_ime_status.clone());
+                return Ok(new_ime_status);
+            }
+        }
+    }
+    fn shutdown(self) -> MessageReceiver {
+        send_fatal_error(
+            &self.context,
+            AppError::CustomError("Receiver shutdown.".to_string()),
+        );
+        drop(self.inner_receiver); // ブロッキングしている送信を解除する。
+        self._worker_handle.join().expect("worker thread panicked.");
+        debug!("ScriptImeReceiver shutdown.");
+
+        self.message_receiver
+    }
+}
+
+impl ImeMainLoop for ScriptImeReceiver {
+    fn main_loop(
+        _context: &AppContext,
+        config: &ScriptImeReceiverConfig,
+        fatal_error: &FatalError,
+    ) -> Result<(), AppError> {
+        script_main_loop(config, fatal_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{catch_fatal_error, initialize_app, initialize_fatal_error};
+
+    use std::io::Write;
+
+    #[test]
+    fn lines_are_neither_replayed_nor_lost_across_restarts() {
+        let path = std::env::temp_dir().join(format!(
+            "kanata_ime_observer_script_{}.txt",
+            std::process::id()
+        ));
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, "mozc\n\nxkb:us::eng\nmozc\nanthy").unwrap();
+        drop(file);
+
+        let config = ScriptImeReceiverConfig {
+            source: ScriptSource::Path(path.clone()),
+            exit_at_eof: true,
+            ..Default::default()
+        };
+        let (context, mut message_receiver, mut fatal_error_receiver) = initialize_app();
+
+        let mut received = Vec::new();
+        for count in [1, 2, 1] {
+            let fatal_error = initialize_fatal_error(&fatal_error_receiver);
+            let mut receiver =
+                ScriptImeReceiver::new(&context, message_receiver, &config, &fatal_error).unwrap();
+            for _ in 0..count {
+                received.push(receiver.receive().unwrap());
+            }
+
+            let catch_handle = std::thread::spawn({
+                let fatal_error = fatal_error.clone();
+                move || {
+                    catch_fatal_error(fatal_error, &fatal_error_receiver);
+                    fatal_error_receiver
+                }
+            });
+            message_receiver = receiver.shutdown();
+            fatal_error_receiver = catch_handle.join().unwrap();
+        }
+        assert_eq!(received, ["mozc", "xkb:us::eng", "mozc", "anthy"]);
+
+        let fatal_error = initialize_fatal_error(&fatal_error_receiver);
+        assert!(matches!(
+            script_main_loop(&config, &fatal_error),
+            Err(AppError::ScriptEnd)
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{
+    AppError,
+    backend::Backend,
+    script::{ScriptImeReceiverConfig, ScriptSource},
+    transport::KanataAddress,
+};
+
+#[cfg(target_os = "linux")]
+use crate::fcitx::FcitxImeReceiverConfig;
+
+#[cfg(target_os = "linux")]
+use crate::ibus::IbusImeReceiverConfig;
+
+#[cfg(all(feature = "winonoff", target_os = "windows"))]
+use crate::win_onoff::WindowsImeOnOffReceiverConfig;
+
+#[cfg(all(not(feature = "winonoff"), target_os = "windows"))]
+use crate::win::WindowsImeReceiverConfig;
+
+#[cfg(target_os = "macos")]
+use crate::mac::MacImeReceiverConfig;
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use serde::de::{IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+/// 設定ファイル(TOML)。コマンドライン引数で指定した値はこちらより優先される。
+/// 他のOSのバックエンドのセクションは無視する。
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// サブコマンド名(config, layer, fakekey, log)。
+    pub mode: Option<String>,
+    #[serde(default, rename = "target")]
+    pub targets: Vec<TargetSettings>,
+    pub debug: Option<bool>,
+    pub strict: Option<bool>,
+    pub policy: Option<String>,
+    pub grace: Option<u64>,
+    pub switch_ime: Option<bool>,
+    pub action: Option<String>,
+    pub backend: Option<Backend>,
+    pub script: Option<ScriptImeReceiverConfig>,
+    /// 別名とバックエンドごとのIME名(パターン)の一覧。定義順に試す。
+    #[serde(default, deserialize_with = "ordered_table")]
+    pub alias: Vec<(String, Vec<String>)>,
+    /// 組み込みの別名を使うかどうか。
+    pub builtin_aliases: Option<bool>,
+
+    #[cfg(target_os = "linux")]
+    pub ibus: Option<IbusImeReceiverConfig>,
+    #[cfg(not(target_os = "linux"))]
+    #[serde(rename = "ibus")]
+    _ibus: Option<IgnoredAny>,
+
+    #[cfg(target_os = "linux")]
+    pub fcitx: Option<FcitxImeReceiverConfig>,
+    #[cfg(not(target_os = "linux"))]
+    #[serde(rename = "fcitx")]
+    _fcitx: Option<IgnoredAny>,
+
+    #[cfg(all(not(feature = "winonoff"), target_os = "windows"))]
+    pub windows: Option<WindowsImeReceiverConfig>,
+    #[cfg(not(all(not(feature = "winonoff"), target_os = "windows")))]
+    #[serde(rename = "windows")]
+    _windows: Option<IgnoredAny>,
+
+    #[cfg(all(feature = "winonoff", target_os = "windows"))]
+    pub win_onoff: Option<WindowsImeOnOffReceiverConfig>,
+    #[cfg(not(all(feature = "winonoff", target_os = "windows")))]
+    #[serde(rename = "win_onoff")]
+    _win_onoff: Option<IgnoredAny>,
+
+    #[cfg(target_os = "macos")]
+    pub mac: Option<MacImeReceiverConfig>,
+    #[cfg(not(target_os = "macos"))]
+    #[serde(rename = "mac")]
+    _mac: Option<IgnoredAny>,
+}
+
+/// 設定ファイルの`[[target]]`。モードに応じて`config`・`file`・`layer`・`key`のいずれかを利用する。
+/// IME名のパターンは定義順に優先されるため、テーブルは定義順に読み込む。
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetSettings {
+    pub address: KanataAddress,
+    /// 順番がkanataの`--cfg`の番号となるIME名。
+    #[serde(default)]
+    pub config: Vec<String>,
+    /// IME名とkanataの設定ファイル。
+    #[serde(default, deserialize_with = "ordered_table")]
+    pub file: Vec<(String, PathBuf)>,
+    /// IME名とレイヤー名。
+    #[serde(default, deserialize_with = "ordered_table")]
+    pub layer: Vec<(String, String)>,
+    /// IME名と仮想キー名。
+    #[serde(default, deserialize_with = "ordered_table")]
+    pub key: Vec<(String, String)>,
+    /// 現在のレイヤーのままとするIME名。
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// どのIMEにも一致しない場合の値。
+    pub default_config: Option<usize>,
+    pub default_file: Option<PathBuf>,
+    pub default_layer: Option<String>,
+    pub default_key: Option<String>,
+    /// フォーカス中のアプリケーションごとのマップ。定義順に優先する。
+    #[serde(default, rename = "app")]
+    pub apps: Vec<AppSettings>,
+}
+
+/// 設定ファイルの`[[target.app]]`。アプリケーションがフォーカス中の場合に`[[target]]`より優先する。
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppSettings {
+    /// app idまたはclass。IME名と同じパターンを利用できる。
+    pub name: String,
+    #[serde(default, deserialize_with = "ordered_table")]
+    pub file: Vec<(String, PathBuf)>,
+    #[serde(default, deserialize_with = "ordered_table")]
+    pub layer: Vec<(String, String)>,
+    #[serde(default, deserialize_with = "ordered_table")]
+    pub key: Vec<(String, String)>,
+    #[serde(default)]
+    pub ignore: Vec<String>,
+}
+
+/// テーブルを定義順のキーと値の組として読み込む。
+fn ordered_table<'de, D, T>(deserializer: D) -> Result<Vec<(String, T)>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct OrderedTableVisitor<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for OrderedTableVisitor<T> {
+        type Value = Vec<(String, T)>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a table")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut entries = Vec::new();
+            while let Some(entry) = map.next_entry()? {
+                entries.push(entry);
+            }
+            Ok(entries)
+        }
+    }
+
+    deserializer.deserialize_map(OrderedTableVisitor(PhantomData))
+}
+
+impl Settings {
+    /// 設定ファイルを読み込む。相対パスは設定ファイルのディレクトリからのパスとする。
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            AppError::SettingsError(format!("Couldn't read '{}': {e}", path.display()))
+        })?;
+
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        Settings::parse(&text, base_dir)
+            .map_err(|message| AppError::SettingsError(format!("'{}': {message}", path.display())))
+    }
+
+    fn parse(text: &str, base_dir: &Path) -> Result<Self, String> {
+        let mut settings: Settings =
+            toml::from_str(text).map_err(|e| describe_toml_error(text, &e))?;
+
+        for target in settings.targets.iter_mut() {
+            for (_, config_file) in target.file.iter_mut() {
+                *config_file = base_dir.join(&config_file);
+            }
+            if let Some(config_file) = target.default_file.as_mut() {
+                *config_file = base_dir.join(&config_file);
+            }
+            for app in target.apps.iter_mut() {
+                for (_, config_file) in app.file.iter_mut() {
+                    *config_file = base_dir.join(&config_file);
+                }
+            }
+        }
+        if let Some(ScriptImeReceiverConfig {
+            source: ScriptSource::Path(path),
+            ..
+        }) = settings.script.as_mut()
+        {
+            *path = base_dir.join(&path);
+        }
+
+        Ok(settings)
+    }
+}
+
+/// パースエラーを行・列を含む1行のメッセージにする。
+fn describe_toml_error(text: &str, e: &toml::de::Error) -> String {
+    let message = e.message().trim_end();
+    match e.span() {
+        Some(span) => {
+            let before = &text[..span.start];
+            let line = before.matches('\n').count() + 1;
+            let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+            format!("line {line}, column {column}: {message}")
+        }
+        None => message.to_string(),
+    }
+}
+
+/// 設定ファイルの既定のパス。`$XDG_CONFIG_HOME/kanata_ime_observer/config.toml`とし、
+/// `XDG_CONFIG_HOME`がない場合は`~/.config`、windowsでは`%APPDATA%`を利用する。
+pub fn default_path() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    let config_dir = std::env::var_os("APPDATA").map(PathBuf::from);
+
+    #[cfg(not(target_os = "windows"))]
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    config_dir.map(|dir| dir.join("kanata_ime_observer").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_settings() {
+        let settings = Settings::parse(
+            r#"
+mode = "layer"
+backend = "script"
+policy = "enforce"
+grace = 500
+builtin_aliases = false
+
+[alias]
+jp = ["mozc-jp", "mozc"]
+
+[[target]]
+address = "49500"
+layer = { mozc = "ja", "xkb:us::eng" = "base" }
+
+[[target]]
+address = "unix:/run/kanata.sock"
+file = { mozc = "ja.kbd", "xkb:us::eng" = "/etc/kanata/us.kbd" }
+
+[[target.app]]
+name = "kitty"
+file = { mozc = "ja-term.kbd" }
+
+[script]
+source = "ime.fifo"
+
+[mac]
+delay = 100
+"#,
+            Path::new("/home/user/.config/kanata_ime_observer"),
+        )
+        .unwrap();
+
+        assert_eq!(settings.mode.as_deref(), Some("layer"));
+        assert_eq!(settings.backend, Some(Backend::Script));
+        assert_eq!(settings.grace, Some(500));
+        assert_eq!(
+            settings.alias,
+            vec![(
+                "jp".to_string(),
+                vec!["mozc-jp".to_string(), "mozc".to_string()]
+            )]
+        );
+        assert_eq!(settings.builtin_aliases, Some(false));
+        assert_eq!(
+            settings.targets[0].address,
+            KanataAddress::Tcp("127.0.0.1:49500".to_string())
+        );
+        assert_eq!(
+            settings.targets[0].layer,
+            vec![
+                ("mozc".to_string(), "ja".to_string()),
+                ("xkb:us::eng".to_string(), "base".to_string())
+            ]
+        );
+        assert_eq!(
+            settings.targets[1].file,
+            vec![
+                (
+                    "mozc".to_string(),
+                    PathBuf::from("/home/user/.config/kanata_ime_observer/ja.kbd")
+                ),
+                (
+                    "xkb:us::eng".to_string(),
+                    PathBuf::from("/etc/kanata/us.kbd")
+                )
+            ]
+        );
+        assert_eq!(settings.targets[1].apps[0].name, "kitty");
+        assert_eq!(
+            settings.targets[1].apps[0].file,
+            vec![(
+                "mozc".to_string(),
+                PathBuf::from("/home/user/.config/kanata_ime_observer/ja-term.kbd")
+            )]
+        );
+        assert_eq!(
+            settings.script.unwrap().source,
+            ScriptSource::Path(PathBuf::from(
+                "/home/user/.config/kanata_ime_observer/ime.fifo"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_error_has_position() {
+        let err = Settings::parse(
+            "mode = \"layer\"\n[[target]]\naddress = \"localhost\"\n",
+            Path::new(""),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "line 3, column 11: ArgError: Invalid address 'localhost'."
+        );
+
+        let err = Settings::parse("mode = \"layer\"\nstrickt = true\n", Path::new("")).unwrap_err();
+        assert!(err.starts_with("line 2, column 1: "), "{err}");
+    }
+}
@@ -0,0 +1,184 @@
+use crate::AppError;
+
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// kanataとの接続。TCP・Unixドメインソケットなどが実装する。
+pub trait KanataTransport: Read + Write + Send {
+    /// 読み込み用と書き込み用に接続を複製する。
+    fn try_clone(&self) -> io::Result<KanataStream>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// 読み書きを終了する。ブロッキングしている読み込みを解除するために呼ぶ。
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+/// 接続方法によらないkanataとの接続。
+pub type KanataStream = Box<dyn KanataTransport>;
+
+impl KanataTransport for TcpStream {
+    fn try_clone(&self) -> io::Result<KanataStream> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl KanataTransport for UnixStream {
+    fn try_clone(&self) -> io::Result<KanataStream> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// kanataの接続先。
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum KanataAddress {
+    /// `host:port`の形式。IPv6は`[::1]:port`とする。
+    Tcp(String),
+    /// Unixドメインソケットのパス。
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl KanataAddress {
+    /// 接続する。TCPの場合は名前解決したアドレスを順に試す。
+    pub fn connect(&self, timeout: Duration) -> Result<KanataStream, AppError> {
+        match self {
+            KanataAddress::Tcp(host_port) => {
+                let mut last_err = None;
+                for addr in host_port.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, timeout) {
+                        Ok(stream) => return Ok(Box::new(stream)),
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+                Err(last_err.map(AppError::from).unwrap_or_else(|| {
+                    AppError::CustomError(format!("Couldn't resolve '{host_port}'."))
+                }))
+            }
+            #[cfg(unix)]
+            KanataAddress::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        }
+    }
+}
+
+impl FromStr for KanataAddress {
+    type Err = AppError;
+
+    /// `PORT`・`HOST:PORT`・`[IPv6]:PORT`・`unix:PATH`を受け付ける。`PORT`のみの場合は127.0.0.1に接続する。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(KanataAddress::Unix(PathBuf::from(path)));
+
+            #[cfg(not(unix))]
+            return Err(AppError::ArgError(format!(
+                "Unix domain socket '{path}' is not supported on this platform."
+            )));
+        }
+
+        if let Ok(port) = s.parse::<u16>() {
+            return Ok(KanataAddress::Tcp(format!("127.0.0.1:{port}")));
+        }
+
+        let invalid = || AppError::ArgError(format!("Invalid address '{s}'."));
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        port.parse::<u16>().map_err(|_| invalid())?;
+        if host.is_empty()
+            || (host.contains(':') && !(host.starts_with('[') && host.ends_with(']')))
+        {
+            return Err(invalid());
+        }
+
+        Ok(KanataAddress::Tcp(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for KanataAddress {
+    type Error = AppError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for KanataAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KanataAddress::Tcp(host_port) => write!(f, "{host_port}"),
+            #[cfg(unix)]
+            KanataAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(
+            "49500".parse::<KanataAddress>().unwrap(),
+            KanataAddress::Tcp("127.0.0.1:49500".to_string())
+        );
+        assert_eq!(
+            "localhost:49500".parse::<KanataAddress>().unwrap(),
+            KanataAddress::Tcp("localhost:49500".to_string())
+        );
+        assert_eq!(
+            "[::1]:49500".parse::<KanataAddress>().unwrap(),
+            KanataAddress::Tcp("[::1]:49500".to_string())
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            "unix:/run/kanata.sock".parse::<KanataAddress>().unwrap(),
+            KanataAddress::Unix(PathBuf::from("/run/kanata.sock"))
+        );
+    }
+
+    #[test]
+    fn invalid_address_is_error() {
+        for s in [
+            "",
+            "localhost",
+            ":49500",
+            "localhost:port",
+            "::1:49500",
+            "70000",
+        ] {
+            assert!(s.parse::<KanataAddress>().is_err(), "{s}");
+        }
+    }
+}
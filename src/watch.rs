@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn};
+
+/// 設定ファイルの再読み込みを通知するためのセンダー。
+pub type ReloadSender = SyncSender<()>;
+
+/// ポーリングで設定ファイルの更新を確認する間隔。
+const POLLING_SPAN: Duration = Duration::from_secs(1);
+
+/// 設定ファイルの変更とSIGHUP(unix)を監視し、再読み込みを通知する。
+/// linuxではinotifyを利用し、利用できない場合はポーリングする。
+pub fn watch_settings(path: PathBuf, reload_sender: ReloadSender) {
+    #[cfg(unix)]
+    watch_sighup(reload_sender.clone());
+
+    std::thread::spawn(move || {
+        #[cfg(target_os = "linux")]
+        match watch_with_inotify(&path, &reload_sender) {
+            Ok(()) => return,
+            Err(e) => {
+                warn!("Couldn't watch the config file with inotify. Fall back to polling: {e}")
+            }
+        }
+
+        watch_with_polling(&path, &reload_sender);
+    });
+}
+
+/// 再読み込みを通知する。既に通知済みの場合は何もしない。受信側が終了した場合はfalseを返す。
+fn notify_reload(reload_sender: &ReloadSender) -> bool {
+    match reload_sender.try_send(()) {
+        Ok(()) | Err(TrySendError::Full(())) => true,
+        Err(TrySendError::Disconnected(())) => false,
+    }
+}
+
+#[cfg(unix)]
+fn watch_sighup(reload_sender: ReloadSender) {
+    use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Couldn't handle SIGHUP: {e}");
+            return;
+        }
+    };
+
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("Got SIGHUP. Reload the config file.");
+            if !notify_reload(&reload_sender) {
+                break;
+            }
+        }
+    });
+}
+
+/// シンボリックリンクの場合のリンク先。リンクでない場合はそのまま返す。
+fn resolve_link(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(target_os = "linux")]
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// エディターは保存時にファイルを置き換えることがあるため、ディレクトリを監視する。
+/// シンボリックリンクの場合はリンクとリンク先の両方のディレクトリを監視し、リンクの付け替えに追従する。
+#[cfg(target_os = "linux")]
+fn watch_with_inotify(path: &Path, reload_sender: &ReloadSender) -> std::io::Result<()> {
+    use inotify::{Inotify, WatchMask};
+
+    let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE;
+    let mut inotify = Inotify::init()?;
+    let link_watch = inotify.watches().add(parent_dir(path), mask)?;
+    let mut target = resolve_link(path);
+    let mut target_watch = inotify.watches().add(parent_dir(&target), mask)?;
+    debug!(
+        "Watching the config file with inotify: {}",
+        target.display()
+    );
+
+    let mut buffer = [0; 4096];
+    loop {
+        let mut events = inotify.read_events_blocking(&mut buffer)?;
+        if !events.any(|event| {
+            (event.wd == link_watch && event.name == path.file_name())
+                || (event.wd == target_watch && event.name == target.file_name())
+        }) {
+            continue;
+        }
+
+        info!("The config file was changed. Reload the config file.");
+        if !notify_reload(reload_sender) {
+            return Ok(());
+        }
+
+        let new_target = resolve_link(path);
+        if new_target != target {
+            debug!("The config file now links to {}.", new_target.display());
+            // 同じディレクトリの監視は同じものになるため、リンクの監視は残す。
+            if target_watch != link_watch {
+                let _ = inotify.watches().remove(target_watch);
+            }
+            target_watch = inotify.watches().add(parent_dir(&new_target), mask)?;
+            target = new_target;
+        }
+    }
+}
+
+/// シンボリックリンクの場合はリンク先と、リンク先の更新時刻を比べる。
+fn watch_with_polling(path: &Path, reload_sender: &ReloadSender) {
+    let modified = |path: &Path| -> (PathBuf, Option<SystemTime>) {
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        (resolve_link(path), modified)
+    };
+
+    debug!("Watching the config file by polling: {}", path.display());
+    let mut pre_modified = modified(path);
+    loop {
+        std::thread::sleep(POLLING_SPAN);
+
+        let new_modified = modified(path);
+        if new_modified != pre_modified {
+            pre_modified = new_modified;
+            info!("The config file was changed. Reload the config file.");
+            if !notify_reload(reload_sender) {
+                return;
+            }
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use crate::{
-    AppError, FatalError, InnerReceiver, Message, MessageReceiver, handle_try_send,
-    send_fatal_error, send_message,
+    AppContext, AppError, FatalError, ImeMainLoop, ImeReceiver, InnerReceiver, Message,
+    MessageReceiver, deserialize_polling_span, handle_try_send, send_fatal_error, send_message,
 };
 
-use std::{collections::HashMap, sync::mpsc::sync_channel, time::Duration};
+use std::{cell::RefCell, collections::HashMap, sync::mpsc::sync_channel, time::Duration};
 
 use windows::Win32::{
     Devices::HumanInterfaceDevice::{HID_USAGE_GENERIC_KEYBOARD, HID_USAGE_PAGE_GENERIC},
@@ -30,6 +30,7 @@ use windows::Win32::{
 use windows::core::w;
 
 use log::{debug, error};
+use serde::Deserialize;
 
 const VK_CONTROL: u16 = windows::Win32::UI::Input::KeyboardAndMouse::VK_CONTROL.0 as _;
 const VK_LCONTROL: u16 = windows::Win32::UI::Input::KeyboardAndMouse::VK_LCONTROL.0 as _; // RawInputではおそらく出力されない
@@ -37,6 +38,20 @@ const VK_RCONTROL: u16 = windows::Win32::UI::Input::KeyboardAndMouse::VK_RCONTRO
 const VK_LWIN: u16 = windows::Win32::UI::Input::KeyboardAndMouse::VK_LWIN.0 as _;
 const VK_RWIN: u16 = windows::Win32::UI::Input::KeyboardAndMouse::VK_RWIN.0 as _;
 
+// フック・ウィンドウプロシージャはメインループのスレッドで呼ばれるため、スレッドローカルにコンテキストを保持する。
+thread_local! {
+    static CONTEXT: RefCell<Option<AppContext>> = const { RefCell::new(None) };
+}
+
+/// コールバックからメインループのコンテキストを利用する。
+fn with_context(f: impl FnOnce(&AppContext)) {
+    CONTEXT.with_borrow(|context| {
+        if let Some(context) = context {
+            f(context);
+        }
+    });
+}
+
 /// フォーカス変更時に実行されるコールバック。これはキーによる変更でもほとんどの場合で呼ばれるが、入力メソッド変更の小ウィンドウに対して行われるため、長押しした場合などに想定した挙動とはならない。
 extern "system" fn win_event_proc(
     _hwineventhook: HWINEVENTHOOK,
@@ -48,7 +63,7 @@ extern "system" fn win_event_proc(
     _dwmseventtime: u32,
 ) {
     if event == EVENT_SYSTEM_FOREGROUND {
-        send_message(Message::GetImeStatus);
+        with_context(|context| send_message(context, Message::GetImeStatus));
     }
 }
 
@@ -86,7 +101,9 @@ extern "system" fn wndproc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM)
                             if let VK_CONTROL | VK_LCONTROL | VK_RCONTROL | VK_LWIN | VK_RWIN =
                                 keyboard.VKey
                             {
-                                send_message(Message::GetImeStatus);
+                                with_context(|context| {
+                                    send_message(context, Message::GetImeStatus)
+                                });
                             }
                         }
                     }
@@ -103,7 +120,9 @@ extern "system" fn wndproc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM)
 }
 
 // winのメインループ。
-pub fn win_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
+pub fn win_main_loop(context: &AppContext, fatal_error: &FatalError) -> Result<(), AppError> {
+    CONTEXT.set(Some(context.clone()));
+
     unsafe {
         // hook
         let hook = SetWinEventHook(
@@ -156,6 +175,8 @@ pub fn win_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
 
         let _ = ShowWindow(hwnd, SW_HIDE);
 
+        send_message(context, Message::GetImeStatus); // 起動時の状態を取得する。
+
         // メッセージループ
         let mut msg = MSG::default();
         while GetMessageW(&mut msg, None, 0, 0).into() && fatal_error.is_none() {
@@ -168,6 +189,8 @@ pub fn win_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
         }
     }
 
+    CONTEXT.set(None);
+
     if fatal_error.is_none() {
         Err(AppError::WinApiError("WM_QUIT received.".to_string()))
     } else {
@@ -266,9 +289,11 @@ fn get_foreground_locale(locale_map: &HashMap<u16, String>) -> Result<String, Ap
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct WindowsImeReceiverConfig {
     pub delay: u64,
+    #[serde(deserialize_with = "deserialize_polling_span")]
     pub polling_span: Option<u64>,
 }
 
@@ -282,14 +307,18 @@ impl Default for WindowsImeReceiverConfig {
 }
 
 pub struct WindowsImeReceiver {
+    context: AppContext,
     _worker_handle: std::thread::JoinHandle<MessageReceiver>,
     _polling_handle: Option<std::thread::JoinHandle<()>>,
     inner_receiver: InnerReceiver,
     pre_ime_status: Option<String>,
 }
 
-impl WindowsImeReceiver {
-    pub fn new(
+impl ImeReceiver for WindowsImeReceiver {
+    type Config = WindowsImeReceiverConfig;
+
+    fn new(
+        context: &AppContext,
         message_receiver: MessageReceiver,
         config: &WindowsImeReceiverConfig,
         fatal_error: &FatalError,
@@ -304,6 +333,7 @@ impl WindowsImeReceiver {
         let locale_map = initialize_locale_map()?;
 
         let _worker_handle = std::thread::spawn({
+            let context = context.clone();
             let fatal_error = fatal_error.clone();
             let delay = *delay;
 
@@ -316,6 +346,7 @@ impl WindowsImeReceiver {
                     match get_foreground_locale(&locale_map) {
                         Ok(locale) => {
                             handle_try_send(
+                                &context,
                                 &inner_sender,
                                 locale,
                                 "WindowsImeReceiver inner sender.".to_string(),
@@ -334,18 +365,20 @@ impl WindowsImeReceiver {
         // ポーリングスレッド
         let _polling_handle = polling_span.map(|polling_span| {
             std::thread::spawn({
+                let context = context.clone();
                 let fatal_error = fatal_error.clone();
 
                 move || {
                     while fatal_error.is_none() {
                         std::thread::sleep(Duration::from_millis(polling_span));
-                        send_message(Message::GetImeStatus);
+                        send_message(&context, Message::GetImeStatus);
                     }
                 }
             })
         });
 
         Ok(Self {
+            context: context.clone(),
             _worker_handle,
             _polling_handle,
             inner_receiver,
@@ -353,7 +386,7 @@ impl WindowsImeReceiver {
         })
     }
 
-    pub fn receive(&mut self) -> Result<String, AppError> {
+    fn receive(&mut self) -> Result<String, AppError> {
         loop {
             let new_ime_status =
                 self.inner_receiver
@@ -374,8 +407,11 @@ impl WindowsImeReceiver {
         }
     }
 
-    pub fn shutdown(self) -> MessageReceiver {
-        send_fatal_error(AppError::CustomError("Receiver shutdown.".to_string()));
+    fn shutdown(self) -> MessageReceiver {
+        send_fatal_error(
+            &self.context,
+            AppError::CustomError("Receiver shutdown.".to_string()),
+        );
         let message_receiver = self._worker_handle.join().expect("worker thread panicked.");
         if let Some(_polling_handle) = self._polling_handle {
             _polling_handle.join().expect("polling thread panicked.");
@@ -385,3 +421,13 @@ impl WindowsImeReceiver {
         message_receiver
     }
 }
+
+impl ImeMainLoop for WindowsImeReceiver {
+    fn main_loop(
+        context: &AppContext,
+        _config: &Self::Config,
+        fatal_error: &FatalError,
+    ) -> Result<(), AppError> {
+        win_main_loop(context, fatal_error)
+    }
+}
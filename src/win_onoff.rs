@@ -1,8 +1,9 @@
 use crate::{
-    AppError, FatalError, InnerReceiver, Message, MessageReceiver, handle_try_send,
-    send_fatal_error, send_message,
+    AppContext, AppError, FatalError, ImeMainLoop, ImeReceiver, InnerReceiver, Message,
+    MessageReceiver, deserialize_polling_span, handle_try_send, send_fatal_error, send_message,
 };
 
+use std::cell::RefCell;
 use std::sync::mpsc::sync_channel;
 use std::time::Duration;
 
@@ -30,6 +31,7 @@ use windows::Win32::{
 use windows::core::w;
 
 use log::{debug, error};
+use serde::Deserialize;
 
 const IMC_GETOPENSTATUS: usize = 0x0005;
 
@@ -40,6 +42,20 @@ const VK_JP_IME_OFF: u16 = 243; // 日本語用
 const VK_JP_EISU: u16 = 240; // 日本語用
 const VK_HANGUL: u16 = windows::Win32::UI::Input::KeyboardAndMouse::VK_HANGUL.0 as _; // ハングル用
 
+// フック・ウィンドウプロシージャはメインループのスレッドで呼ばれるため、スレッドローカルにコンテキストを保持する。
+thread_local! {
+    static CONTEXT: RefCell<Option<AppContext>> = const { RefCell::new(None) };
+}
+
+/// コールバックからメインループのコンテキストを利用する。
+fn with_context(f: impl FnOnce(&AppContext)) {
+    CONTEXT.with_borrow(|context| {
+        if let Some(context) = context {
+            f(context);
+        }
+    });
+}
+
 /// フォーカス変更時に実行されるコールバック
 extern "system" fn win_event_proc(
     _hwineventhook: HWINEVENTHOOK,
@@ -51,7 +67,7 @@ extern "system" fn win_event_proc(
     _dwmseventtime: u32,
 ) {
     if event == EVENT_SYSTEM_FOREGROUND {
-        send_message(Message::GetImeStatus);
+        with_context(|context| send_message(context, Message::GetImeStatus));
     }
 }
 
@@ -168,7 +184,9 @@ extern "system" fn wndproc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM)
                             if let VK_JP_IME_ON | VK_JP_IME_OFF | VK_JP_EISU | VK_IME_ON
                             | VK_IME_OFF | VK_HANGUL = keyboard.VKey
                             {
-                                send_message(Message::GetImeStatus);
+                                with_context(|context| {
+                                    send_message(context, Message::GetImeStatus)
+                                });
                             }
                         }
                     }
@@ -185,7 +203,9 @@ extern "system" fn wndproc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM)
 }
 
 // winのメインループ。
-pub fn win_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
+pub fn win_main_loop(context: &AppContext, fatal_error: &FatalError) -> Result<(), AppError> {
+    CONTEXT.set(Some(context.clone()));
+
     unsafe {
         // hook
         let hook = SetWinEventHook(
@@ -238,6 +258,8 @@ pub fn win_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
 
         let _ = ShowWindow(hwnd, SW_HIDE);
 
+        send_message(context, Message::GetImeStatus); // 起動時の状態を取得する。
+
         // メッセージループ
         let mut msg = MSG::default();
         while GetMessageW(&mut msg, None, 0, 0).into() && fatal_error.is_none() {
@@ -250,6 +272,8 @@ pub fn win_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
         }
     }
 
+    CONTEXT.set(None);
+
     if fatal_error.is_none() {
         Err(AppError::WinApiError("WM_QUIT received.".to_string()))
     } else {
@@ -259,12 +283,14 @@ pub fn win_main_loop(fatal_error: &FatalError) -> Result<(), AppError> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct WindowsImeOnOffReceiverConfig {
     pub retry_number: usize,
     pub send_message_timeout: u32,
     pub retry_span: u64,
     pub delay: u64,
+    #[serde(deserialize_with = "deserialize_polling_span")]
     pub polling_span: Option<u64>,
 }
 
@@ -281,14 +307,18 @@ impl Default for WindowsImeOnOffReceiverConfig {
 }
 
 pub struct WindowsImeOnOffReceiver {
+    context: AppContext,
     _worker_handle: std::thread::JoinHandle<MessageReceiver>,
     _polling_handle: Option<std::thread::JoinHandle<()>>,
     inner_receiver: InnerReceiver,
     pre_ime_status: Option<String>,
 }
 
-impl WindowsImeOnOffReceiver {
-    pub fn new(
+impl ImeReceiver for WindowsImeOnOffReceiver {
+    type Config = WindowsImeOnOffReceiverConfig;
+
+    fn new(
+        context: &AppContext,
         message_receiver: MessageReceiver,
         config: &WindowsImeOnOffReceiverConfig,
         fatal_error: &FatalError,
@@ -305,6 +335,7 @@ impl WindowsImeOnOffReceiver {
 
         // workerスレッド
         let _worker_handle = std::thread::spawn({
+            let context = context.clone();
             let fatal_error = fatal_error.clone();
             let delay = *delay;
             let retry_number = *retry_number;
@@ -320,6 +351,7 @@ impl WindowsImeOnOffReceiver {
                     match get_window_ime_status(retry_number, send_message_timeout, retry_span) {
                         Ok(response) => {
                             handle_try_send(
+                                &context,
                                 &inner_sender,
                                 response,
                                 "WindowsImeOnOffReceiver inner sender.".to_string(),
@@ -338,24 +370,26 @@ impl WindowsImeOnOffReceiver {
         // ポーリングスレッド
         let _polling_handle = polling_span.map(|polling_span| {
             std::thread::spawn({
+                let context = context.clone();
                 let fatal_error = fatal_error.clone();
                 move || {
                     while fatal_error.is_none() {
                         std::thread::sleep(Duration::from_millis(polling_span));
-                        send_message(Message::GetImeStatus);
+                        send_message(&context, Message::GetImeStatus);
                     }
                 }
             })
         });
 
         Ok(Self {
+            context: context.clone(),
             _worker_handle,
             _polling_handle,
             inner_receiver,
             pre_ime_status: None,
         })
     }
-    pub fn receive(&mut self) -> Result<String, AppError> {
+    fn receive(&mut self) -> Result<String, AppError> {
         loop {
             let new_ime_status =
                 self.inner_receiver
@@ -375,8 +409,11 @@ impl WindowsImeOnOffReceiver {
             }
         }
     }
-    pub fn shutdown(self) -> MessageReceiver {
-        send_fatal_error(AppError::CustomError("Receiver shutdown.".to_string()));
+    fn shutdown(self) -> MessageReceiver {
+        send_fatal_error(
+            &self.context,
+            AppError::CustomError("Receiver shutdown.".to_string()),
+        );
         let message_receiver = self._worker_handle.join().expect("worker thread panicked.");
         if let Some(_polling_handle) = self._polling_handle {
             _polling_handle.join().expect("polling thread panicked.");
@@ -386,3 +423,13 @@ impl WindowsImeOnOffReceiver {
         message_receiver
     }
 }
+
+impl ImeMainLoop for WindowsImeOnOffReceiver {
+    fn main_loop(
+        context: &AppContext,
+        _config: &Self::Config,
+        fatal_error: &FatalError,
+    ) -> Result<(), AppError> {
+        win_main_loop(context, fatal_error)
+    }
+}